@@ -1,136 +1,681 @@
+pub mod avatar_config;
+pub mod error;
+pub mod listener;
 pub mod pen_handle;
+pub mod session;
 
 use std::{
-    net::{SocketAddrV4, UdpSocket},
-    str::FromStr,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
 };
 
-use anyhow::Result;
-use rosc::OscPacket;
+pub use error::{Result, VrcCanvasError};
+use listener::{AvatarParameterUpdate, AvatarParameterValue, ParameterValues};
+pub use session::Session;
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, watch},
+    task::JoinHandle,
+};
 
 pub const DEFAULT_BASE_ADDR: &str = "/avatar/parameters";
 pub const DEFAULT_ADDR: &str = "";
 
 pub const DEFAULT_IP_ADDR: &str = "127.0.0.1";
-pub const DEFAULT_RECEIVER_OSC_PORT: usize = 9000;
-pub const DEFAULT_SENDER_OSC_PORT: usize = 9001;
+pub const DEFAULT_RECEIVER_OSC_PORT: u16 = 9000;
+pub const DEFAULT_SENDER_OSC_PORT: u16 = 9001;
+
+/// Endpoints to communicate with VRChat.
+///
+/// The ports follow the naming of VRChat's `--osc=inPort:senderIP:outPort` launch option.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OscConfig {
+    /// The host VRChat is running on.
+    pub host: String,
+    /// The port VRChat receives OSC messages on.
+    pub in_port: u16,
+    /// The port VRChat sends OSC messages to.
+    pub out_port: u16,
+    /// The address prefix of every parameter sent.
+    pub base_addr: String,
+    /// Whether to listen on the out port for messages from VRChat.
+    pub inbound: bool,
+}
+
+impl Default for OscConfig {
+    fn default() -> Self {
+        Self {
+            host: DEFAULT_IP_ADDR.to_string(),
+            in_port: DEFAULT_RECEIVER_OSC_PORT,
+            out_port: DEFAULT_SENDER_OSC_PORT,
+            base_addr: DEFAULT_BASE_ADDR.to_string(),
+            inbound: false,
+        }
+    }
+}
+
+impl OscConfig {
+    /// The address VRChat receives OSC messages on.
+    pub fn receiver_addr(&self) -> Result<SocketAddr> {
+        Self::resolve(&self.host, self.in_port)
+    }
+
+    /// The local address to listen on for the messages VRChat sends to the out port.
+    ///
+    /// VRChat may run on another machine, so it listens on every interface instead of the host.
+    pub fn listen_addr(&self) -> Result<SocketAddr> {
+        Ok(local_addr(self.receiver_addr()?, self.out_port))
+    }
+
+    /// The full OSC address of the given parameter.
+    pub fn parameter_addr(&self, addr: &str) -> String {
+        format!("{}{}{}", self.base_addr, DEFAULT_ADDR, addr)
+    }
+
+    fn resolve(host: &str, port: u16) -> Result<SocketAddr> {
+        let unresolved = |source| VrcCanvasError::UnresolvedHost {
+            host: host.to_string(),
+            source,
+        };
+
+        (host, port)
+            .to_socket_addrs()
+            .map_err(|e| unresolved(Some(e)))?
+            .next()
+            .ok_or_else(|| unresolved(None))
+    }
+}
+
+/// Bind a socket to the given address, explaining the case where another process owns the port.
+///
+/// This binds synchronously so the sockets can be (re)bound from the UI thread,
+/// hence it must be called within the tokio runtime.
+fn bind_socket(addr: SocketAddr) -> Result<Arc<UdpSocket>> {
+    let socket = std::net::UdpSocket::bind(addr).map_err(|e| VrcCanvasError::bind(addr, e))?;
+    socket.set_nonblocking(true)?;
+
+    Ok(Arc::new(UdpSocket::from_std(socket)?))
+}
+
+/// The address of every local interface on the port, of the same family as the remote address.
+fn local_addr(remote_addr: SocketAddr, port: u16) -> SocketAddr {
+    if remote_addr.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))
+    }
+}
+
+/// Bind a socket to an ephemeral port to send messages to the given address.
+fn bind_sender(receiver_addr: SocketAddr) -> Result<Arc<UdpSocket>> {
+    bind_socket(local_addr(receiver_addr, 0))
+}
+
+/// Bind a socket to listen on the out port if the inbound features are enabled.
+fn bind_receiver(config: &OscConfig, receiver_addr: SocketAddr) -> Result<Option<Arc<UdpSocket>>> {
+    if !config.inbound {
+        return Ok(None);
+    }
+
+    let listen_addr = local_addr(receiver_addr, config.out_port);
+    let socket = bind_socket(listen_addr)?;
+    log::info!("Started recieving on {}", listen_addr);
+
+    Ok(Some(socket))
+}
 
 #[derive(Debug)]
 pub struct OscHandler {
-    pub socket: UdpSocket,
+    config: RwLock<OscConfig>,
+    /// The address VRChat receives on, resolved when (re)binding not to look up the host at every packet.
+    receiver_addr: RwLock<SocketAddr>,
+    /// The socket bound to an ephemeral port to send messages.
+    sender: RwLock<Arc<UdpSocket>>,
+    /// The task receiving on the out port, only running when the inbound features are enabled.
+    listener: Mutex<Option<JoinHandle<()>>>,
+    updates: broadcast::Sender<AvatarParameterUpdate>,
+    /// The last value of every parameter VRChat has reported.
+    values: ParameterValues,
+    /// The id of the avatar VRChat has reported last.
+    avatar: Arc<watch::Sender<Option<String>>>,
 }
 
-pub static OSC_HANDLER: once_cell::sync::OnceCell<OscHandler> = once_cell::sync::OnceCell::new();
-
-pub const CANVAS_UPDATE_LATENCY_DEFAULT: std::time::Duration =
-    std::time::Duration::from_millis(100);
+/// The timetag meaning the bundle should be applied immediately.
+pub const OSC_TIME_IMMEDIATELY: rosc::OscTime = rosc::OscTime {
+    seconds: 0,
+    fractional: 1,
+};
 
 impl OscHandler {
-    pub fn init_hadler() -> Result<()> {
-        let sender_addr = SocketAddrV4::from_str(
-            format!("{}:{}", DEFAULT_IP_ADDR, DEFAULT_SENDER_OSC_PORT).as_str(),
-        )?;
+    /// Bind the sockets, and start receiving if the inbound features are enabled.
+    pub fn new(config: OscConfig) -> Result<Self> {
+        let receiver_addr = config.receiver_addr()?;
+        let receiver = bind_receiver(&config, receiver_addr)?;
 
         let handler = OscHandler {
-            socket: UdpSocket::bind(sender_addr)?,
+            sender: RwLock::new(bind_sender(receiver_addr)?),
+            receiver_addr: RwLock::new(receiver_addr),
+            listener: Mutex::new(None),
+            updates: broadcast::channel(listener::PARAMETER_UPDATES_CAPACITY).0,
+            values: ParameterValues::default(),
+            avatar: Arc::new(watch::channel(None).0),
+            config: RwLock::new(config),
         };
+        handler.listen(receiver);
 
-        if let Err(e) = OSC_HANDLER.set(handler) {
-            anyhow::bail!("failed to init osc handler: {:?}", e);
-        } else {
-            log::info!("Started recieving from {}", sender_addr);
-        }
+        Ok(handler)
+    }
+
+    /// The handler of the session started by [`start_osc`].
+    pub fn get_handler() -> Result<Arc<OscHandler>> {
+        Session::current()
+            .map(|session| session.osc().clone())
+            .ok_or(VrcCanvasError::NotInitialized("OSC Handler"))
+    }
+
+    pub fn config(&self) -> OscConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    fn sender(&self) -> Arc<UdpSocket> {
+        self.sender.read().unwrap().clone()
+    }
+
+    /// The address VRChat receives on, as resolved when (re)binding.
+    pub fn receiver_addr(&self) -> SocketAddr {
+        *self.receiver_addr.read().unwrap()
+    }
+
+    async fn send(&self, packet: &rosc::OscPacket) -> Result<()> {
+        let receiver_addr = self.receiver_addr();
+
+        let encoded_data = rosc::encoder::encode(packet).map_err(VrcCanvasError::Encode)?;
+
+        self.sender()
+            .send_to(encoded_data.as_slice(), receiver_addr)
+            .await
+            .map_err(|source| VrcCanvasError::Unreachable {
+                addr: receiver_addr,
+                source,
+            })?;
+        log::trace!("Sending {:?} to {}", packet, &receiver_addr);
 
         Ok(())
     }
 
-    pub fn get_handler() -> Result<&'static OscHandler> {
-        OSC_HANDLER
-            .get()
-            .ok_or(anyhow::anyhow!("OSC Handler is not initialized"))
+    /// Send the message to the address relative to the base address.
+    pub async fn send_packet(&self, addr: &str, value: Vec<rosc::OscType>) -> Result<()> {
+        let addr = self.config().parameter_addr(addr);
+
+        let packet = rosc::OscPacket::Message(rosc::OscMessage {
+            addr: addr.to_string(),
+            args: value,
+        });
+
+        self.send(&packet).await
     }
-}
 
-pub fn start_osc(current_state: Option<pen_handle::PenState>) -> Result<()> {
-    OscHandler::init_hadler()?;
+    /// Send the messages at once in a bundle, so VRChat applies all of them in the same frame.
+    ///
+    /// The bundle is delivered immediately unless the time to deliver it is given.
+    pub async fn send_bundle(
+        &self,
+        messages: Vec<(&str, Vec<rosc::OscType>)>,
+        timetag: Option<SystemTime>,
+    ) -> Result<()> {
+        let config = self.config();
 
-    pen_handle::PenHandler::init(None)?;
+        let timetag = match timetag {
+            Some(time) => {
+                rosc::OscTime::try_from(time).map_err(|_| VrcCanvasError::InvalidTimetag(time))?
+            }
+            None => OSC_TIME_IMMEDIATELY,
+        };
 
-    tokio::spawn(async {
-        loop {
-            let handler = pen_handle::PEN_HANDLER.get().unwrap().lock().await;
-            handler.eval().await;
-            tokio::time::sleep(CANVAS_UPDATE_LATENCY_DEFAULT).await;
+        let content = messages
+            .into_iter()
+            .map(|(addr, value)| {
+                rosc::OscPacket::Message(rosc::OscMessage {
+                    addr: config.parameter_addr(addr),
+                    args: value,
+                })
+            })
+            .collect();
+
+        let packet = rosc::OscPacket::Bundle(rosc::OscBundle { timetag, content });
+
+        self.send(&packet).await
+    }
+
+    /// Subscribe the avatar parameter updates reported by VRChat.
+    pub fn subscribe(&self) -> broadcast::Receiver<AvatarParameterUpdate> {
+        self.updates.subscribe()
+    }
+
+    /// The last value VRChat has reported for the parameter, only known with the inbound features enabled.
+    ///
+    /// The address is relative to the base address as in [`send_packet`].
+    pub fn parameter_value(&self, addr: &str) -> Option<AvatarParameterValue> {
+        let addr = self.config().parameter_addr(addr);
+        self.values.read().unwrap().get(&addr).copied()
+    }
+
+    /// The id of the avatar VRChat has reported last, only known with the inbound features enabled.
+    pub fn current_avatar(&self) -> Option<String> {
+        self.avatar.borrow().clone()
+    }
+
+    /// Subscribe the changes of the avatar reported by VRChat.
+    pub fn subscribe_avatar(&self) -> watch::Receiver<Option<String>> {
+        self.avatar.subscribe()
+    }
+
+    /// Wait until the parameter reports a value satisfying the predicate, or the timeout fires.
+    ///
+    /// Returns right away if the last value reported already satisfies it.
+    /// The address is relative to the base address as in [`send_packet`].
+    pub async fn expect_parameter_with<F>(
+        &self,
+        addr: &str,
+        mut predicate: F,
+        timeout: Duration,
+    ) -> Result<AvatarParameterUpdate>
+    where
+        F: FnMut(&AvatarParameterValue) -> bool,
+    {
+        if !self.is_listening() {
+            return Err(VrcCanvasError::InboundDisabled);
         }
-    });
 
-    Ok(())
-}
+        let addr = self.config().parameter_addr(addr);
+        // Subscribe before looking up the last value, not to miss the update in between.
+        let mut updates = self.subscribe();
+        let last = self.values.read().unwrap().get(&addr).copied();
+        if let Some(value) = last.filter(|value| predicate(value)) {
+            return Ok(AvatarParameterUpdate { addr, value });
+        }
 
-pub fn receive_packet(buf: &mut [u8]) -> Result<OscPacket> {
-    let handler = OscHandler::get_handler()?;
+        let deadline = tokio::time::Instant::now() + timeout;
 
-    let socket = &handler.socket;
+        loop {
+            match tokio::time::timeout_at(deadline, updates.recv()).await {
+                Ok(Ok(update)) => {
+                    if update.addr == addr && predicate(&update.value) {
+                        return Ok(update);
+                    }
+                }
+                Ok(Err(broadcast::error::RecvError::Lagged(skipped))) => {
+                    log::warn!(
+                        "Skipped {} parameter updates while expecting {}",
+                        skipped,
+                        addr
+                    );
+                }
+                Ok(Err(broadcast::error::RecvError::Closed)) => {
+                    return Err(VrcCanvasError::StoppedReceiving { addr });
+                }
+                Err(_) => {
+                    return Err(VrcCanvasError::Timeout { addr, timeout });
+                }
+            }
+        }
+    }
 
-    match socket.recv_from(buf) {
-        Ok((size, addr)) => {
-            let (_buf, packet) = rosc::decoder::decode_udp(&buf[..size])?;
-            log::info!("Received {:?} from {}", packet, addr);
+    /// Wait until the parameter reports the value, or the timeout fires.
+    pub async fn expect_parameter(
+        &self,
+        addr: &str,
+        value: AvatarParameterValue,
+        timeout: Duration,
+    ) -> Result<AvatarParameterUpdate> {
+        self.expect_parameter_with(addr, |v| *v == value, timeout)
+            .await
+    }
 
-            return Ok(packet);
+    /// Start the task receiving on the given socket, after the previous one has been stopped.
+    fn listen(&self, receiver: Option<Arc<UdpSocket>>) {
+        let mut listener = self.listener.lock().unwrap();
+
+        if let Some(task) = listener.take() {
+            // Only when it hasn't been stopped, which may keep the port bound for a while.
+            task.abort();
         }
-        Err(e) => {
-            log::error!("Error receiving from socket: {}", e);
-            return Err(anyhow::anyhow!("Error receiving from socket: {}", e));
+
+        *listener = receiver.map(|socket| {
+            tokio::spawn(listener::receive_loop(
+                socket,
+                self.updates.clone(),
+                self.values.clone(),
+                self.avatar.clone(),
+            ))
+        });
+    }
+
+    /// Whether receiving from VRChat, which only happens with the inbound features enabled.
+    pub fn is_listening(&self) -> bool {
+        self.listener.lock().unwrap().is_some()
+    }
+
+    /// Stop receiving, releasing the out port for the other applications.
+    ///
+    /// Returns once the listening task has dropped the socket, so the port can be bound again right away.
+    pub async fn stop(&self) {
+        let task = self.listener.lock().unwrap().take();
+
+        if let Some(task) = task {
+            task.abort();
+            // The loop never ends by itself, so it only returns the cancellation.
+            let _ = task.await;
         }
+        // The values may change while not receiving.
+        self.values.write().unwrap().clear();
     }
-}
 
-/*
-pub async fn expect_packet(addr: &str, value: Vec<OscType>) -> Result<OscPacket> {
-    let mut osc_buffer = [0u8; rosc::decoder::MTU];
+    /// Apply the new config, rebinding the sockets only if the addresses to bind have changed.
+    pub async fn rebind(&self, config: OscConfig) -> Result<()> {
+        let current = self.config();
+        let current_addr = self.receiver_addr();
 
-    loop {
-        let packet = receive_packet(&mut osc_buffer)?;
-        handle_packet(&packet);
-        if let OscPacket::Message(msg) = packet.clone() {
-            if msg.addr == addr && msg.args == value {
-                return Ok(packet);
-            }
+        let receiver_addr = config.receiver_addr()?;
+        if current_addr.is_ipv4() != receiver_addr.is_ipv4() {
+            *self.sender.write().unwrap() = bind_sender(receiver_addr)?;
+        }
+
+        if current.inbound != config.inbound
+            || local_addr(current_addr, current.out_port)
+                != local_addr(receiver_addr, config.out_port)
+        {
+            // Release the out port first, since the new socket may bind to the same one.
+            self.stop().await;
+
+            let receiver = match bind_receiver(&config, receiver_addr) {
+                Ok(receiver) => receiver,
+                Err(e) => {
+                    // Keep receiving as before, so a failure keeps the session alive.
+                    let previous = bind_receiver(&current, current_addr).unwrap_or_else(|e| {
+                        log::error!("Failed to receive on the previous port again: {}", e);
+                        None
+                    });
+                    self.listen(previous);
+                    return Err(e);
+                }
+            };
+            self.listen(receiver);
         }
+
+        *self.receiver_addr.write().unwrap() = receiver_addr;
+        *self.config.write().unwrap() = config;
+
+        Ok(())
     }
 }
-*/
 
-fn handle_packet(packet: &OscPacket) {
-    match packet {
-        OscPacket::Message(msg) => {
-            log::info!("message: {} {:?}", msg.addr, msg.args);
-        }
-        OscPacket::Bundle(bundle) => {
-            log::info!("bundle: {:?}", bundle);
+impl Drop for OscHandler {
+    fn drop(&mut self) {
+        // Release the out port at least eventually, since the listening task holds the socket.
+        // Stop it with `stop` beforehand to release the port right away.
+        if let Some(task) = self.listener.get_mut().unwrap().take() {
+            task.abort();
         }
     }
 }
 
-pub fn send_packet(addr: &str, value: Vec<rosc::OscType>) -> Result<()> {
-    let socket = &OscHandler::get_handler()?.socket;
+/// Start the session kept globally, which can be started again after [`stop_osc`].
+///
+/// Use [`Session::start`] instead to run several sessions at once.
+pub fn start_osc(current_state: Option<pen_handle::PenState>, config: OscConfig) -> Result<()> {
+    let mut session = session::SESSION.write().unwrap();
+    if session.is_some() {
+        return Err(VrcCanvasError::AlreadyStarted);
+    }
 
-    let receiver_addr = SocketAddrV4::from_str(
-        format!("{}:{}", DEFAULT_IP_ADDR, DEFAULT_RECEIVER_OSC_PORT).as_str(),
-    )?;
+    *session = Some(Session::start(current_state, config)?);
 
-    let addr = format!("{}{}{}", DEFAULT_BASE_ADDR, DEFAULT_ADDR, addr);
+    Ok(())
+}
 
-    let packet = rosc::OscPacket::Message(rosc::OscMessage {
-        addr: addr.to_string(),
-        args: value,
-    });
+/// Stop the session kept globally, after lifting the pen and sending the reset values.
+pub async fn stop_osc() {
+    // Take it first, so the next session can start without waiting.
+    let session = session::SESSION.write().unwrap().take();
 
-    let encoded_data = rosc::encoder::encode(&packet)?;
+    if let Some(session) = session {
+        session.stop().await;
+    }
+}
 
-    socket.send_to(encoded_data.as_slice(), receiver_addr)?;
-    log::info!("Sending {:?} to {}/{}", &packet, &receiver_addr, &addr);
+/// Stop the session kept globally and start it again with the config.
+pub async fn restart_osc(
+    current_state: Option<pen_handle::PenState>,
+    config: OscConfig,
+) -> Result<()> {
+    stop_osc().await;
+    start_osc(current_state, config)
+}
 
-    Ok(())
+/// Subscribe the avatar parameter updates reported by VRChat.
+pub fn subscribe() -> Result<broadcast::Receiver<AvatarParameterUpdate>> {
+    Ok(OscHandler::get_handler()?.subscribe())
+}
+
+/// Wait until the parameter reports the value, or the timeout fires.
+///
+/// e.g. `expect_parameter("/Pen_Enabled", AvatarParameterValue::Bool(true), timeout)`
+pub async fn expect_parameter(
+    addr: &str,
+    value: AvatarParameterValue,
+    timeout: Duration,
+) -> Result<AvatarParameterUpdate> {
+    OscHandler::get_handler()?
+        .expect_parameter(addr, value, timeout)
+        .await
+}
+
+/// Wait until the parameter reports a value satisfying the predicate, or the timeout fires.
+pub async fn expect_parameter_with<F>(
+    addr: &str,
+    predicate: F,
+    timeout: Duration,
+) -> Result<AvatarParameterUpdate>
+where
+    F: FnMut(&AvatarParameterValue) -> bool,
+{
+    OscHandler::get_handler()?
+        .expect_parameter_with(addr, predicate, timeout)
+        .await
+}
+
+pub async fn send_packet(addr: &str, value: Vec<rosc::OscType>) -> Result<()> {
+    OscHandler::get_handler()?.send_packet(addr, value).await
+}
+
+/// Send the messages at once in a bundle, so VRChat applies all of them in the same frame.
+///
+/// The bundle is delivered immediately unless the time to deliver it is given.
+pub async fn send_bundle(
+    messages: Vec<(&str, Vec<rosc::OscType>)>,
+    timetag: Option<SystemTime>,
+) -> Result<()> {
+    OscHandler::get_handler()?
+        .send_bundle(messages, timetag)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inbound_config(out_port: u16) -> OscConfig {
+        OscConfig {
+            host: "127.0.0.1".to_string(),
+            out_port,
+            inbound: true,
+            ..Default::default()
+        }
+    }
+
+    /// A port free at the moment, which the test binds again right after.
+    fn free_port() -> u16 {
+        std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stop_releases_out_port() {
+        let config = inbound_config(free_port());
+
+        let handler = OscHandler::new(config.clone()).unwrap();
+        assert!(handler.is_listening());
+        handler.stop().await;
+        assert!(!handler.is_listening());
+
+        // Restarting binds the same port again right away.
+        let restarted = OscHandler::new(config).unwrap();
+        restarted.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rebind_toggles_inbound() {
+        let config = inbound_config(free_port());
+        let handler = OscHandler::new(config.clone()).unwrap();
+
+        handler
+            .rebind(OscConfig {
+                inbound: false,
+                ..config.clone()
+            })
+            .await
+            .unwrap();
+        assert!(!handler.is_listening());
+
+        handler.rebind(config).await.unwrap();
+        assert!(handler.is_listening());
+        handler.stop().await;
+    }
+
+    /// Send the packet to the out port as VRChat does.
+    fn report(out_port: u16, packet: &rosc::OscPacket) {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let encoded = rosc::encoder::encode(packet).unwrap();
+        socket.send_to(&encoded, ("127.0.0.1", out_port)).unwrap();
+    }
+
+    fn parameter(addr: &str, arg: rosc::OscType) -> rosc::OscPacket {
+        rosc::OscPacket::Message(rosc::OscMessage {
+            addr: addr.to_string(),
+            args: vec![arg],
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn expect_parameter_returns_last_value() {
+        let config = inbound_config(free_port());
+        let handler = OscHandler::new(config.clone()).unwrap();
+
+        report(
+            config.out_port,
+            &parameter(
+                &config.parameter_addr("/Pen_Enabled"),
+                rosc::OscType::Bool(true),
+            ),
+        );
+        let reported = handler
+            .expect_parameter(
+                "/Pen_Enabled",
+                AvatarParameterValue::Bool(true),
+                Duration::from_secs(1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reported.addr, config.parameter_addr("/Pen_Enabled"));
+
+        // VRChat doesn't report it again while it doesn't change.
+        let expected = handler
+            .expect_parameter(
+                "/Pen_Enabled",
+                AvatarParameterValue::Bool(true),
+                Duration::from_millis(100),
+            )
+            .await
+            .unwrap();
+        assert_eq!(expected, reported);
+        assert_eq!(
+            handler.parameter_value("/Pen_Enabled"),
+            Some(AvatarParameterValue::Bool(true))
+        );
+
+        assert!(matches!(
+            handler
+                .expect_parameter(
+                    "/Pen_Enabled",
+                    AvatarParameterValue::Bool(false),
+                    Duration::from_millis(100),
+                )
+                .await,
+            Err(VrcCanvasError::Timeout { .. })
+        ));
+
+        handler.stop().await;
+        assert_eq!(handler.parameter_value("/Pen_Enabled"), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn send_bundle_timetag() {
+        // Stand in for VRChat on the in port.
+        let vrchat = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        vrchat
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let config = OscConfig {
+            host: "127.0.0.1".to_string(),
+            in_port: vrchat.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        let handler = OscHandler::new(config.clone()).unwrap();
+
+        let receive = || {
+            let mut buf = [0u8; rosc::decoder::MTU];
+            let size = vrchat.recv(&mut buf).unwrap();
+            match rosc::decoder::decode_udp(&buf[..size]).unwrap().1 {
+                rosc::OscPacket::Bundle(bundle) => bundle,
+                packet => panic!("Not a bundle: {:?}", packet),
+            }
+        };
+
+        handler
+            .send_bundle(
+                vec![
+                    ("/Pen_X", vec![rosc::OscType::Float(0.25)]),
+                    ("/Pen_Y", vec![rosc::OscType::Float(0.75)]),
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+        let bundle = receive();
+        assert_eq!(bundle.timetag, OSC_TIME_IMMEDIATELY);
+        assert_eq!(
+            bundle.content,
+            [
+                parameter(&config.parameter_addr("/Pen_X"), rosc::OscType::Float(0.25)),
+                parameter(&config.parameter_addr("/Pen_Y"), rosc::OscType::Float(0.75)),
+            ]
+        );
+
+        let time = SystemTime::now() + Duration::from_millis(50);
+        handler
+            .send_bundle(
+                vec![("/Pen_X", vec![rosc::OscType::Float(0.5)])],
+                Some(time),
+            )
+            .await
+            .unwrap();
+        assert_eq!(receive().timetag, rosc::OscTime::try_from(time).unwrap());
+
+        handler.stop().await;
+    }
 }
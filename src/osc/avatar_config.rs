@@ -0,0 +1,288 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use super::{
+    pen_handle::profile::{AvatarProfile, ParameterBinding, ParameterType},
+    OscConfig, Result, VrcCanvasError,
+};
+
+/// The config VRChat writes in `OSC/usr_*/Avatars/avtr_*.json` for every avatar it has loaded.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AvatarConfig {
+    pub id: String,
+    pub name: String,
+    pub parameters: Vec<AvatarConfigParameter>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AvatarConfigParameter {
+    pub name: String,
+    /// Where VRChat receives the parameter, missing for the read-only ones such as `VelocityX`.
+    pub input: Option<AvatarConfigEndpoint>,
+    /// Where VRChat sends the parameter.
+    pub output: Option<AvatarConfigEndpoint>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AvatarConfigEndpoint {
+    /// The full OSC address, such as `/avatar/parameters/Pen_X`.
+    pub address: String,
+    #[serde(rename = "type")]
+    pub ty: ParameterType,
+}
+
+/// What is wrong with a channel of the profile on the avatar.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MappingIssue {
+    /// The avatar has no parameter to receive the channel at the address.
+    Missing { channel: &'static str, addr: String },
+    /// The parameter exists, but is of the other type than the channel is sent as.
+    WrongType {
+        channel: &'static str,
+        addr: String,
+        expected: ParameterType,
+        found: ParameterType,
+    },
+}
+
+impl std::fmt::Display for MappingIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing { channel, addr } => {
+                write!(f, "{}: the avatar has no parameter at {}", channel, addr)
+            }
+            Self::WrongType {
+                channel,
+                addr,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: {} is {:?} on the avatar, not {:?}",
+                channel, addr, found, expected
+            ),
+        }
+    }
+}
+
+/// The accessor of an optional channel of the profile.
+type Channel = fn(&mut AvatarProfile) -> &mut Option<ParameterBinding>;
+
+/// The words the parameter names of the optional channels end with, in the order of the preference.
+const CHANNEL_KEYWORDS: [(&[&str], ParameterType, Channel); 10] = [
+    (&["z", "depth"], ParameterType::Float, |profile| {
+        &mut profile.z
+    }),
+    (
+        &["enabled", "enable", "active"],
+        ParameterType::Bool,
+        |profile| &mut profile.enabled,
+    ),
+    (
+        &["drawing", "draw", "down", "ink"],
+        ParameterType::Bool,
+        |profile| &mut profile.pen_down,
+    ),
+    (
+        &["width", "size", "thickness"],
+        ParameterType::Float,
+        |profile| &mut profile.width,
+    ),
+    (&["color", "colour"], ParameterType::Int, |profile| {
+        &mut profile.color
+    }),
+    (&["hue"], ParameterType::Float, |profile| &mut profile.hue),
+    (&["saturation", "sat"], ParameterType::Float, |profile| {
+        &mut profile.saturation
+    }),
+    (&["value", "brightness"], ParameterType::Float, |profile| {
+        &mut profile.value
+    }),
+    (&["eraser", "erase"], ParameterType::Bool, |profile| {
+        &mut profile.eraser
+    }),
+    (&["clear"], ParameterType::Bool, |profile| {
+        &mut profile.clear
+    }),
+];
+
+impl AvatarConfig {
+    pub fn from_json(s: &str) -> Result<Self, String> {
+        // VRChat writes the files with the byte order mark.
+        serde_json::from_str(s.trim_start_matches('\u{feff}')).map_err(|e| e.to_string())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let s = std::fs::read_to_string(path).map_err(|e| VrcCanvasError::InvalidAvatarConfig {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })?;
+
+        Self::from_json(&s).map_err(|reason| VrcCanvasError::InvalidAvatarConfig {
+            path: path.to_path_buf(),
+            reason,
+        })
+    }
+
+    /// The parameter VRChat receives at the full address.
+    pub fn input(&self, address: &str) -> Option<&AvatarConfigEndpoint> {
+        self.parameters
+            .iter()
+            .filter_map(|parameter| parameter.input.as_ref())
+            .find(|input| input.address == address)
+    }
+
+    /// Check every channel of the profile is received by the avatar as the type it is sent as.
+    pub fn validate(&self, profile: &AvatarProfile, config: &OscConfig) -> Vec<MappingIssue> {
+        profile
+            .channels()
+            .into_iter()
+            .filter_map(|(channel, binding)| {
+                let addr = config.parameter_addr(&binding.addr);
+                match self.input(&addr) {
+                    None => Some(MappingIssue::Missing { channel, addr }),
+                    Some(input) if input.ty != binding.ty => Some(MappingIssue::WrongType {
+                        channel,
+                        addr,
+                        expected: binding.ty,
+                        found: input.ty,
+                    }),
+                    Some(_) => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Guess the profile from the parameter names, such as `Pen_X` for the x channel.
+    ///
+    /// Returns `None` when the avatar has no parameters for the x and y channels.
+    pub fn suggest_profile(&self, config: &OscConfig) -> Option<AvatarProfile> {
+        let prefix = config.parameter_addr("");
+        let x = self.suggest_binding(&prefix, &["x"], ParameterType::Float)?;
+        let y = self.suggest_binding(&prefix, &["y"], ParameterType::Float)?;
+
+        // Keep the ranges of the built-in profile for the position.
+        let default = AvatarProfile::default();
+        let mut profile = AvatarProfile {
+            name: self.name.clone(),
+            avatars: vec![self.id.clone()],
+            x: ParameterBinding {
+                addr: x.addr,
+                ..default.x
+            },
+            y: ParameterBinding {
+                addr: y.addr,
+                ..default.y
+            },
+            ..default
+        };
+
+        for (keywords, ty, channel) in CHANNEL_KEYWORDS {
+            *channel(&mut profile) = self.suggest_binding(&prefix, keywords, ty);
+        }
+
+        Some(profile)
+    }
+
+    fn suggest_binding(
+        &self,
+        prefix: &str,
+        keywords: &[&str],
+        ty: ParameterType,
+    ) -> Option<ParameterBinding> {
+        let candidates = self.parameters.iter().filter_map(|parameter| {
+            let input = parameter.input.as_ref().filter(|input| input.ty == ty)?;
+            let addr = input.address.strip_prefix(prefix)?;
+            let name = normalize_name(&parameter.name);
+            let keyword = keywords
+                .iter()
+                .position(|keyword| name.ends_with(keyword))?;
+
+            // Prefer the earlier keywords, the pen parameters, then the shorter names.
+            Some(((keyword, !name.contains("pen"), name.len()), addr))
+        });
+
+        candidates
+            .min_by_key(|(rank, _)| *rank)
+            .map(|(_, addr)| ParameterBinding::new(addr, ty))
+    }
+}
+
+/// Lowercase the name without the separators, so `Pen_X` and `penX` are alike.
+fn normalize_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The directory VRChat writes the OSC configs in, which can be changed by the users.
+///
+/// It is only known on Windows, where VRChat runs natively.
+pub fn default_osc_dir() -> Option<PathBuf> {
+    let mut dir = PathBuf::from(std::env::var_os("USERPROFILE")?);
+    dir.extend(["AppData", "LocalLow", "VRChat", "VRChat", "OSC"]);
+
+    Some(dir)
+}
+
+/// Every avatar config in `usr_*/Avatars/avtr_*.json` under the OSC directory.
+pub fn find_configs(osc_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+
+    for user in std::fs::read_dir(osc_dir)? {
+        let user = user?.path();
+        if !has_file_name(&user, "usr_", None) {
+            continue;
+        }
+
+        // Not every user has loaded the avatars with OSC yet.
+        let avatars = match std::fs::read_dir(user.join("Avatars")) {
+            Ok(avatars) => avatars,
+            Err(_) => continue,
+        };
+        paths.extend(
+            avatars
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| has_file_name(path, "avtr_", Some("json"))),
+        );
+    }
+
+    Ok(paths)
+}
+
+fn has_file_name(path: &Path, prefix: &str, extension: Option<&str>) -> bool {
+    let name_matches = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(prefix));
+
+    match extension {
+        Some(ext) => name_matches && path.extension().is_some_and(|e| e == ext),
+        None => name_matches,
+    }
+}
+
+/// Load the config of the avatar, or the one written last when the avatar isn't known.
+///
+/// VRChat writes the config when loading the avatar, so the latest one is usually of the current avatar.
+pub fn load_config(osc_dir: &Path, avatar_id: Option<&str>) -> Result<AvatarConfig> {
+    let paths = find_configs(osc_dir)?;
+
+    let path = match avatar_id {
+        Some(id) => paths
+            .into_iter()
+            .find(|path| path.file_stem().is_some_and(|stem| stem == id)),
+        None => paths
+            .into_iter()
+            .max_by_key(|path| path.metadata().and_then(|m| m.modified()).ok()),
+    };
+
+    match path {
+        Some(path) => AvatarConfig::load(&path),
+        None => Err(VrcCanvasError::AvatarConfigNotFound(
+            avatar_id.unwrap_or("any avatar").to_string(),
+        )),
+    }
+}
@@ -0,0 +1,71 @@
+use std::{io, net::SocketAddr, path::PathBuf, time::Duration, time::SystemTime};
+
+/// The result of the OSC and the pen operations.
+pub type Result<T, E = VrcCanvasError> = std::result::Result<T, E>;
+
+/// The errors of the OSC and the pen layers, so the callers can tell them apart,
+/// e.g. to offer changing the port when it is in use.
+#[derive(Debug, thiserror::Error)]
+pub enum VrcCanvasError {
+    /// Another process, usually another OSC application, owns the port.
+    #[error(
+        "The port {port} is already in use by another process. \
+         Close the other OSC application listening on it or change the out port."
+    )]
+    PortInUse { port: u16 },
+    #[error("Couldn't bind to {addr}: {source}")]
+    Bind {
+        addr: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Couldn't resolve the host: {host}")]
+    UnresolvedHost {
+        host: String,
+        #[source]
+        source: Option<io::Error>,
+    },
+    #[error("Couldn't send to {addr}: {source}")]
+    Unreachable {
+        addr: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to encode the packet: {0}")]
+    Encode(#[source] rosc::OscError),
+    #[error("Failed to decode the packet: {0}")]
+    Decode(#[source] rosc::OscError),
+    #[error("Invalid timetag {0:?}")]
+    InvalidTimetag(SystemTime),
+    /// The session isn't started, or has been stopped.
+    #[error("{0} is not initialized")]
+    NotInitialized(&'static str),
+    #[error("failed to start osc: already started")]
+    AlreadyStarted,
+    #[error("Inbound OSC is not enabled")]
+    InboundDisabled,
+    #[error("Stopped receiving while expecting {addr}")]
+    StoppedReceiving { addr: String },
+    #[error("Timed out after {timeout:?} while expecting {addr}")]
+    Timeout { addr: String, timeout: Duration },
+    #[error("Couldn't load the profile {}: {reason}", path.display())]
+    InvalidProfile { path: PathBuf, reason: String },
+    #[error("Couldn't load the avatar config {}: {reason}", path.display())]
+    InvalidAvatarConfig { path: PathBuf, reason: String },
+    #[error("Couldn't find the avatar config of {0}")]
+    AvatarConfigNotFound(String),
+    #[error("The pen has stopped receiving the commands")]
+    PenStopped,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl VrcCanvasError {
+    /// Classify the error binding to the address.
+    pub(crate) fn bind(addr: SocketAddr, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::AddrInUse => Self::PortInUse { port: addr.port() },
+            _ => Self::Bind { addr, source },
+        }
+    }
+}
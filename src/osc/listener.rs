@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use rosc::{OscMessage, OscPacket, OscType};
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, watch},
+};
+
+use super::VrcCanvasError;
+
+/// The number of updates kept for subscribers lagging behind.
+pub const PARAMETER_UPDATES_CAPACITY: usize = 256;
+
+/// The address VRChat reports the id of the avatar changed into.
+pub const AVATAR_CHANGE_ADDR: &str = "/avatar/change";
+
+/// The last value of every parameter reported by VRChat, by the full OSC address.
+///
+/// VRChat only reports the values which have changed, so the ones which haven't are looked up here.
+pub type ParameterValues = Arc<RwLock<HashMap<String, AvatarParameterValue>>>;
+
+/// A value of an avatar parameter reported by VRChat.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AvatarParameterValue {
+    Bool(bool),
+    Int(i32),
+    Float(f32),
+}
+
+impl AvatarParameterValue {
+    pub fn from_osc(arg: &OscType) -> Option<Self> {
+        match arg {
+            OscType::Bool(v) => Some(Self::Bool(*v)),
+            OscType::Int(v) => Some(Self::Int(*v)),
+            OscType::Float(v) => Some(Self::Float(*v)),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> f32 {
+        match self {
+            Self::Bool(v) => *v as i32 as f32,
+            Self::Int(v) => *v as f32,
+            Self::Float(v) => *v,
+        }
+    }
+}
+
+/// An update of an avatar parameter reported by VRChat.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvatarParameterUpdate {
+    /// The full OSC address of the parameter.
+    pub addr: String,
+    pub value: AvatarParameterValue,
+}
+
+impl AvatarParameterUpdate {
+    fn from_message(msg: &OscMessage) -> Option<Self> {
+        let value = match msg.args.as_slice() {
+            [arg] => AvatarParameterValue::from_osc(arg)?,
+            _ => return None,
+        };
+
+        Some(Self {
+            addr: msg.addr.clone(),
+            value,
+        })
+    }
+}
+
+/// Collect every message in the packet, flattening nested bundles.
+pub fn flatten_packet(packet: OscPacket, messages: &mut Vec<OscMessage>) {
+    match packet {
+        OscPacket::Message(msg) => messages.push(msg),
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                flatten_packet(packet, messages);
+            }
+        }
+    }
+}
+
+fn handle_packet(
+    packet: OscPacket,
+    updates: &broadcast::Sender<AvatarParameterUpdate>,
+    values: &ParameterValues,
+    avatar: &watch::Sender<Option<String>>,
+) {
+    let mut messages = Vec::new();
+    flatten_packet(packet, &mut messages);
+
+    for msg in messages {
+        if let (AVATAR_CHANGE_ADDR, [OscType::String(id)]) =
+            (msg.addr.as_str(), msg.args.as_slice())
+        {
+            log::info!("The avatar has changed into {}", id);
+            // The values were of the other avatar, and VRChat reports the new ones after this.
+            values.write().unwrap().clear();
+            avatar.send_replace(Some(id.clone()));
+            continue;
+        }
+
+        match AvatarParameterUpdate::from_message(&msg) {
+            Some(update) => {
+                log::trace!("Received {:?}", update);
+                values
+                    .write()
+                    .unwrap()
+                    .insert(update.addr.clone(), update.value);
+                // It only fails when nobody subscribes.
+                let _ = updates.send(update);
+            }
+            None => {
+                log::debug!("Ignored message: {} {:?}", msg.addr, msg.args);
+            }
+        }
+    }
+}
+
+/// Keep receiving from the socket and dispatch the parameter updates to the subscribers.
+pub(crate) async fn receive_loop(
+    socket: Arc<UdpSocket>,
+    updates: broadcast::Sender<AvatarParameterUpdate>,
+    values: ParameterValues,
+    avatar: Arc<watch::Sender<Option<String>>>,
+) {
+    let mut buf = [0u8; rosc::decoder::MTU];
+
+    loop {
+        let size = match socket.recv_from(&mut buf).await {
+            Ok((size, _addr)) => size,
+            Err(e) => {
+                log::error!("Error receiving from socket: {}", e);
+                continue;
+            }
+        };
+
+        match rosc::decoder::decode_udp(&buf[..size]) {
+            Ok((_buf, packet)) => handle_packet(packet, &updates, &values, &avatar),
+            Err(e) => log::error!("{}", VrcCanvasError::Decode(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(addr: &str, args: Vec<OscType>) -> OscPacket {
+        OscPacket::Message(OscMessage {
+            addr: addr.to_string(),
+            args,
+        })
+    }
+
+    fn bundle(content: Vec<OscPacket>) -> OscPacket {
+        OscPacket::Bundle(rosc::OscBundle {
+            timetag: (0, 1).into(),
+            content,
+        })
+    }
+
+    fn channels() -> (
+        broadcast::Sender<AvatarParameterUpdate>,
+        broadcast::Receiver<AvatarParameterUpdate>,
+        ParameterValues,
+        watch::Sender<Option<String>>,
+    ) {
+        let (updates, receiver) = broadcast::channel(PARAMETER_UPDATES_CAPACITY);
+        let (avatar, _) = watch::channel(None);
+        (updates, receiver, ParameterValues::default(), avatar)
+    }
+
+    #[test]
+    fn flatten_nested_bundles() {
+        let packet = bundle(vec![
+            message("/a", vec![]),
+            bundle(vec![
+                message("/b", vec![]),
+                bundle(vec![message("/c", vec![])]),
+            ]),
+            message("/d", vec![]),
+        ]);
+
+        let mut messages = Vec::new();
+        flatten_packet(packet, &mut messages);
+
+        let addrs = messages
+            .iter()
+            .map(|msg| msg.addr.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, ["/a", "/b", "/c", "/d"]);
+    }
+
+    #[test]
+    fn ignore_unknown_arguments() {
+        let (updates, mut receiver, values, avatar) = channels();
+
+        let packet = bundle(vec![
+            message("/avatar/parameters/None", vec![]),
+            message(
+                "/avatar/parameters/Pair",
+                vec![OscType::Float(0.1), OscType::Float(0.2)],
+            ),
+            message(
+                "/avatar/parameters/Name",
+                vec![OscType::String("pen".to_string())],
+            ),
+            message("/avatar/parameters/Pen_X", vec![OscType::Float(0.5)]),
+        ]);
+        handle_packet(packet, &updates, &values, &avatar);
+
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            AvatarParameterUpdate {
+                addr: "/avatar/parameters/Pen_X".to_string(),
+                value: AvatarParameterValue::Float(0.5),
+            }
+        );
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(values.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn avatar_change_clears_values() {
+        let (updates, mut receiver, values, avatar) = channels();
+        let mut avatar_changes = avatar.subscribe();
+
+        handle_packet(
+            message("/avatar/parameters/Pen_X", vec![OscType::Float(0.5)]),
+            &updates,
+            &values,
+            &avatar,
+        );
+        assert!(!values.read().unwrap().is_empty());
+
+        handle_packet(
+            message(
+                AVATAR_CHANGE_ADDR,
+                vec![OscType::String("avtr_pen".to_string())],
+            ),
+            &updates,
+            &values,
+            &avatar,
+        );
+
+        assert!(avatar_changes.has_changed().unwrap());
+        assert_eq!(
+            avatar_changes.borrow_and_update().as_deref(),
+            Some("avtr_pen")
+        );
+        assert!(values.read().unwrap().is_empty());
+        // Only the parameter is dispatched, not the avatar change.
+        assert!(receiver.try_recv().is_ok());
+        assert!(receiver.try_recv().is_err());
+    }
+}
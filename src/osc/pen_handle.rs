@@ -1,34 +1,128 @@
-use crate::osc;
-use anyhow::Result;
+pub mod actor;
+pub mod brush;
+pub mod color;
+pub mod command;
+pub mod coordinate;
+pub mod feedback;
+pub mod motion;
+pub mod profile;
+
+use crate::osc::{self, OscHandler, Result, VrcCanvasError};
+use brush::{BrushConfig, Stylus};
+use color::{ColorConfig, ColorMode, PenColor};
+use command::PenCommand;
+use coordinate::{CoordinateMapping, Point};
+use feedback::{FeedbackConfig, MoveAck, PositionWatcher};
+use motion::MotionProfile;
+use profile::{AvatarProfile, ParameterBinding};
 use rosc::OscType;
-use tokio::sync;
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
 
-pub static PEN_HANDLER: once_cell::sync::OnceCell<sync::Mutex<PenHandler>> =
-    once_cell::sync::OnceCell::new();
+/// The interval between the intermediate points sent along a stroke.
+pub const SAMPLE_INTERVAL_DEFAULT: std::time::Duration = std::time::Duration::from_millis(20);
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug)]
 pub struct PenHandler {
-    target_state: Option<PenState>,
+    /// The transport of the session the pen belongs to.
+    osc: Arc<OscHandler>,
     current_state: PenState,
-    speed: f32,
+    /// Publishes every change of the current state.
+    states: watch::Sender<PenState>,
+    /// Publishes the acknowledgement of every move confirmed with the feedback.
+    acks: broadcast::Sender<MoveAck>,
+    /// Whether the pen has been enabled on the avatar.
+    enabled: bool,
+    mapping: CoordinateMapping,
+    motion: MotionProfile,
+    settle: SettleDelays,
+    feedback: FeedbackConfig,
+    brush: BrushConfig,
+    reset: ResetValues,
+    color: PenColor,
+    color_config: ColorConfig,
+    profile: AvatarProfile,
 }
 
+/// The settings of the pen, applied by the actor between the commands.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PenSettings {
+    pub mapping: CoordinateMapping,
+    pub motion: MotionProfile,
+    pub settle: SettleDelays,
+    pub feedback: FeedbackConfig,
+    pub brush: BrushConfig,
+    pub color_config: ColorConfig,
+    pub reset: ResetValues,
+    /// The interval between the intermediate points sent along a stroke.
+    pub tick_rate: std::time::Duration,
+    /// The avatar parameters the pen is sent to.
+    pub profile: AvatarProfile,
+}
+
+impl Default for PenSettings {
+    fn default() -> Self {
+        Self {
+            mapping: CoordinateMapping::default(),
+            motion: MotionProfile::default(),
+            settle: SettleDelays::default(),
+            feedback: FeedbackConfig::default(),
+            brush: BrushConfig::default(),
+            color_config: ColorConfig::default(),
+            reset: ResetValues::default(),
+            tick_rate: SAMPLE_INTERVAL_DEFAULT,
+            profile: AvatarProfile::default(),
+        }
+    }
+}
+
+/// The values sent when the session stops, after lifting the pen.
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub enum PenState {
-    Idle(f32, f32),
-    Drawing(f32, f32),
+pub struct ResetValues {
+    pub enabled: bool,
+    /// The parameter values of the pen position, not normalized in the canvas.
+    pub position: (f32, f32),
+    /// Whether to disable the pen on the avatar.
+    pub disable_pen: bool,
+}
+
+impl Default for ResetValues {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            position: (0.0, 0.0),
+            disable_pen: true,
+        }
+    }
+}
+
+/// How long to wait for the avatar after lowering and lifting the pen.
+///
+/// These keep the ink trail from smearing the lines connecting separate strokes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SettleDelays {
+    /// The delay after lowering the pen before drawing.
+    pub pen_down: std::time::Duration,
+    /// The delay after lifting the pen before travelling.
+    pub pen_up: std::time::Duration,
 }
 
-impl Default for PenHandler {
+impl Default for SettleDelays {
     fn default() -> Self {
         Self {
-            target_state: None,
-            current_state: PenState::default(),
-            speed: 1000.0,
+            pen_down: std::time::Duration::from_millis(50),
+            pen_up: std::time::Duration::from_millis(50),
         }
     }
 }
 
+/// The state of the pen at the position normalized in the canvas.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PenState {
+    Idle(f32, f32),
+    Drawing(f32, f32, Stylus),
+}
+
 impl Default for PenState {
     fn default() -> Self {
         Self::Idle(0.0, 0.0)
@@ -36,178 +130,716 @@ impl Default for PenState {
 }
 
 impl PenState {
-    pub fn idle_from_pos(pos: eframe::egui::Pos2) -> Self {
+    pub fn idle_from_pos(pos: Point) -> Self {
         Self::Idle(pos.x, pos.y)
     }
 
-    pub fn drawing_from_pos(pos: eframe::egui::Pos2) -> Self {
-        Self::Drawing(pos.x, pos.y)
+    pub fn drawing_from_pos(pos: Point, stylus: Stylus) -> Self {
+        Self::Drawing(pos.x, pos.y, stylus)
     }
 
     pub fn enable_drawing(&mut self) {
         if let Self::Idle(x, y) = self {
-            *self = Self::Drawing(*x, *y);
+            *self = Self::Drawing(*x, *y, Stylus::default());
+        }
+    }
+
+    pub fn position(&self) -> (f32, f32) {
+        match *self {
+            Self::Idle(x, y) => (x, y),
+            Self::Drawing(x, y, _) => (x, y),
+        }
+    }
+
+    /// The stylus input, only while drawing.
+    pub fn stylus(&self) -> Option<Stylus> {
+        match *self {
+            Self::Idle(..) => None,
+            Self::Drawing(_, _, stylus) => Some(stylus),
+        }
+    }
+
+    /// The same state at the other position.
+    pub fn with_position(&self, (x, y): (f32, f32)) -> Self {
+        match self {
+            Self::Idle(..) => Self::Idle(x, y),
+            Self::Drawing(_, _, stylus) => Self::Drawing(x, y, *stylus),
         }
     }
 }
 
 impl PenHandler {
-    pub fn new(current_state: PenState, speed: Option<f32>) -> Self {
-        let target_state = None;
-        let speed = speed.unwrap_or(0.0);
-
+    /// The settings are the defaults until the actor applies [`PenSettings`].
+    pub fn new(osc: Arc<OscHandler>, current_state: PenState) -> Self {
         Self {
-            target_state,
+            osc,
             current_state,
-            speed,
+            states: watch::channel(current_state).0,
+            acks: broadcast::channel(feedback::MOVE_ACKS_CAPACITY).0,
+            enabled: false,
+            mapping: CoordinateMapping::default(),
+            motion: MotionProfile::default(),
+            settle: SettleDelays::default(),
+            feedback: FeedbackConfig::default(),
+            brush: BrushConfig::default(),
+            reset: ResetValues::default(),
+            color: PenColor::default(),
+            color_config: ColorConfig::default(),
+            profile: AvatarProfile::default(),
         }
     }
 
-    pub fn init(current_state: Option<PenState>) -> Result<()> {
-        match PEN_HANDLER.set(sync::Mutex::new(Self::default())) {
-            Ok(_) => Ok(()),
-            Err(e) => anyhow::bail!("failed to init pen handler: {:?}", e),
-        }
+    pub fn subscribe_state(&self) -> watch::Receiver<PenState> {
+        self.states.subscribe()
+    }
+
+    /// The sender of the move acknowledgements, to subscribe them after the pen has been moved to the actor.
+    pub fn acks(&self) -> broadcast::Sender<MoveAck> {
+        self.acks.clone()
     }
 }
 
 impl PenHandler {
-    const MOV_PREFIX: &str = "/Pen_";
-    const ON_MOVING: &str = "/Pen_Enabled";
+    pub fn apply_settings(&mut self, settings: &PenSettings) {
+        self.set_mapping(settings.mapping);
+        self.set_motion(settings.motion);
+        self.set_settle(settings.settle);
+        self.set_feedback(settings.feedback);
+        self.set_brush(settings.brush);
+        self.set_color_config(settings.color_config);
+        self.set_reset(settings.reset);
+        self.set_profile(settings.profile.clone());
+    }
 
-    const RIGHT: &str = "right";
-    const LEFT: &str = "left";
-    const UP: &str = "up";
-    const DOWN: &str = "down";
+    pub fn set_mapping(&mut self, mapping: CoordinateMapping) {
+        self.mapping = mapping;
+    }
+
+    pub fn set_motion(&mut self, motion: MotionProfile) {
+        self.motion = motion;
+    }
+
+    pub fn set_settle(&mut self, settle: SettleDelays) {
+        self.settle = settle;
+    }
 
-    pub fn set_target_state(mut self, target_state: Option<PenState>) -> Self {
-        self.target_state = target_state;
-        self
+    pub fn set_feedback(&mut self, feedback: FeedbackConfig) {
+        self.feedback = feedback;
+    }
+
+    pub fn set_brush(&mut self, brush: BrushConfig) {
+        self.brush = brush;
+    }
+
+    pub fn set_reset(&mut self, reset: ResetValues) {
+        self.reset = reset;
+    }
+
+    pub fn set_color(&mut self, color: PenColor) {
+        self.color = color;
+    }
+
+    pub fn set_color_config(&mut self, color_config: ColorConfig) {
+        self.color_config = color_config;
+    }
+
+    pub fn set_profile(&mut self, profile: AvatarProfile) {
+        self.profile = profile;
     }
 
     fn set_current_state(&mut self, state: PenState) {
         self.current_state = state;
+        self.states.send_replace(state);
+    }
+
+    /// The motion limits of the profile, falling back to the ones in the settings.
+    fn motion(&self) -> MotionProfile {
+        self.profile.motion.unwrap_or(self.motion)
+    }
+
+    /// The brush width for the stylus input, only if the brush is enabled.
+    fn width(&self, stylus: Option<Stylus>) -> Option<f32> {
+        stylus
+            .filter(|_| self.brush.enabled)
+            .map(|stylus| self.brush.width(stylus))
     }
 
-    fn draw(&self) -> Result<()> {
-        crate::osc::send_packet("/on_drawing", vec![OscType::Bool(true)])?;
+    /// The messages of the color for the stroke about to start.
+    fn color_messages(&self) -> Vec<(&str, Vec<OscType>)> {
+        if !self.color_config.enabled {
+            return Vec::new();
+        }
+
+        let (hue, saturation, value) = self.color.hsv;
+        let profile = &self.profile;
+        match profile.color_mode() {
+            ColorMode::PaletteIndex => vec![channel_message(profile.color.as_ref(), |binding| {
+                binding.raw(self.color.index as f32)
+            })],
+            ColorMode::Hsv => vec![
+                channel_message(profile.hue.as_ref(), |binding| binding.scaled(hue)),
+                channel_message(profile.saturation.as_ref(), |binding| {
+                    binding.scaled(saturation)
+                }),
+                channel_message(profile.value.as_ref(), |binding| binding.scaled(value)),
+            ],
+        }
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Send the argument to the channel, only if the avatar has it.
+    async fn send_channel(
+        &self,
+        binding: Option<&ParameterBinding>,
+        arg: impl FnOnce(&ParameterBinding) -> OscType,
+    ) -> Result<()> {
+        match channel_message(binding, arg) {
+            Some((addr, args)) => self.osc.send_packet(addr, args).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Send the messages in a bundle, so VRChat applies all of them in the same frame.
+    async fn send_channels(&self, messages: Vec<(&str, Vec<OscType>)>) -> Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        self.osc.send_bundle(messages, None).await
+    }
+
+    /// Lower the pen and wait for it to settle.
+    async fn draw(&self, stylus: Option<Stylus>) -> Result<()> {
+        let profile = &self.profile;
+        let eraser = stylus.is_some_and(|stylus| stylus.eraser);
+        let width = self.width(stylus);
+
+        // Set the color, the eraser and the width along with lowering the pen,
+        // not to start the stroke with the last ones.
+        let mut messages = self.color_messages();
+        messages.extend(
+            [
+                channel_message(profile.eraser.as_ref(), |binding| binding.switch(eraser)),
+                width.and_then(|width| {
+                    channel_message(profile.width.as_ref(), |binding| binding.scaled(width))
+                }),
+                channel_message(profile.pen_down.as_ref(), |binding| binding.switch(true)),
+                channel_message(profile.z.as_ref(), |binding| binding.switch(true)),
+            ]
+            .into_iter()
+            .flatten(),
+        );
+        self.send_channels(messages).await?;
+
+        tokio::time::sleep(self.settle.pen_down).await;
         Ok(())
     }
 
-    fn calc_delta(&self) -> (f32, f32) {
-        let targegt_position = if let Some(targegt_position) = self.target_state {
-            match targegt_position {
-                PenState::Idle(x, y) => (x, y),
-                PenState::Drawing(x, y) => (x, y),
-            }
-        } else {
-            return (0.0, 0.0);
-        };
+    /// Lift the pen and wait for it to settle.
+    async fn lift(&self) -> Result<()> {
+        let profile = &self.profile;
+        let messages = [
+            channel_message(profile.pen_down.as_ref(), |binding| binding.switch(false)),
+            channel_message(profile.z.as_ref(), |binding| binding.switch(false)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        self.send_channels(messages).await?;
+
+        tokio::time::sleep(self.settle.pen_up).await;
+        Ok(())
+    }
+
+    /// Enable the pen on the avatar before moving it for the first time.
+    async fn enable(&mut self) -> Result<()> {
+        if !self.enabled {
+            self.send_channel(self.profile.enabled.as_ref(), |binding| {
+                binding.switch(true)
+            })
+            .await?;
+            self.enabled = true;
+        }
+        Ok(())
+    }
+
+    /// Clear the drawing on the avatar, switching the clear channel on and back off.
+    async fn clear(&self) -> Result<()> {
+        if self.profile.clear.is_none() {
+            log::warn!("The profile {} has no clear channel", self.profile.name);
+            return Ok(());
+        }
 
-        let current_position = match self.current_state {
-            PenState::Idle(x, y) => (x, y),
-            PenState::Drawing(x, y) => (x, y),
+        self.send_channel(self.profile.clear.as_ref(), |binding| binding.switch(true))
+            .await?;
+        tokio::time::sleep(self.settle.pen_up).await;
+        self.send_channel(self.profile.clear.as_ref(), |binding| binding.switch(false))
+            .await
+    }
+
+    /// Send the parameter values of the position.
+    async fn send_position(&self, value: (f32, f32), width: Option<f32>) -> Result<()> {
+        let (x, y) = (&self.profile.x, &self.profile.y);
+
+        // Send both axes and the width in a bundle, so they are applied in the same frame.
+        let mut messages = vec![
+            (x.addr.as_str(), vec![x.raw(value.0)]),
+            (y.addr.as_str(), vec![y.raw(value.1)]),
+        ];
+        if let (Some(width), Some(binding)) = (width, &self.profile.width) {
+            messages.push((binding.addr.as_str(), vec![binding.scaled(width)]));
+        }
+
+        self.osc.send_bundle(messages, None).await
+    }
+
+    async fn _mov_to(&mut self, pos: (f32, f32), width: Option<f32>) -> Result<()> {
+        let (x, y) = self.mapping.to_logical(pos);
+        let value = (self.profile.x.map(x), self.profile.y.map(y));
+
+        log::trace!("Is moving to {:?}", pos);
+
+        if !self.feedback.enabled {
+            return self.send_position(value, width).await;
+        }
+        if !self.osc.is_listening() {
+            return Err(VrcCanvasError::InboundDisabled);
+        }
+
+        // Subscribe before sending, not to miss the reports.
+        // The axis which doesn't change is never reported again, so it starts from the last values.
+        let config = self.osc.config();
+        let updates = self.osc.subscribe();
+        let last_value = |binding: &ParameterBinding| {
+            self.osc
+                .parameter_value(&binding.addr)
+                .map(|value| value.as_f32())
         };
+        let mut watcher = PositionWatcher::new(
+            updates,
+            config.parameter_addr(&self.profile.x.addr),
+            config.parameter_addr(&self.profile.y.addr),
+            (last_value(&self.profile.x), last_value(&self.profile.y)),
+        );
+
+        let started = std::time::Instant::now();
+        let mut attempts = 0;
+        let mut reached = false;
+
+        // Resend the position to correct the drift until it is reached.
+        while !reached && attempts <= self.feedback.max_retries {
+            attempts += 1;
+            self.send_position(value, width).await?;
+            reached = watcher
+                .wait_for(value, self.feedback.tolerance, self.feedback.timeout)
+                .await;
+        }
 
-        (
-            targegt_position.0 - current_position.0,
-            targegt_position.1 - current_position.1,
-        )
+        feedback::publish(
+            &self.acks,
+            MoveAck {
+                target: value,
+                reported: watcher.reported(),
+                latency: started.elapsed(),
+                attempts,
+                reached,
+            },
+        );
+
+        // Keep going along the stroke, since the later positions may still be reached.
+        Ok(())
     }
 
-    async fn _mov_to(&self, pos: (f32, f32)) -> Result<()> {
-        let speed = self.speed;
+    /// Move along the path through the positions, sending the intermediate points along the motion profile.
+    ///
+    /// The motion is planned along the whole path, so the points closer than a tick's travel are passed through
+    /// instead of spending a tick on each of them.
+    /// The pen keeps lifted or lowered while moving, tapering the width towards the stylus input while drawing.
+    async fn move_along(
+        &mut self,
+        path: &[((f32, f32), Option<Stylus>)],
+        ticker: &mut tokio::time::Interval,
+    ) -> Result<()> {
+        if path.is_empty() {
+            return Ok(());
+        }
+
+        let mut vertices = vec![(self.current_state.position(), self.current_state.stylus())];
+        vertices.extend_from_slice(path);
+        // The distance along the path to each vertex.
+        let mut ends = vec![0.0];
+        for pair in vertices.windows(2) {
+            let ((from, _), (to, _)) = (pair[0], pair[1]);
+            let length = ((to.0 - from.0).powi(2) + (to.1 - from.1).powi(2)).sqrt();
+            ends.push(ends[ends.len() - 1] + length);
+        }
+        let length = ends[ends.len() - 1];
+        let distances = self.motion().plan(length, ticker.period());
 
-        osc::send_packet(
-            format!("{}X", Self::MOV_PREFIX).as_str(),
-            vec![OscType::Float(pos.0 / 100.0)],
-        )?;
-        osc::send_packet(
-            format!("{}Y", Self::MOV_PREFIX).as_str(),
-            vec![OscType::Float(pos.1 / 100.0)],
-        )?;
+        let mut segment = 1;
+        for distance in distances {
+            ticker.tick().await;
 
-        log::info!("Is moving to {:?}", pos);
+            while segment < vertices.len() - 1 && ends[segment] < distance {
+                segment += 1;
+            }
+            let ((from, from_stylus), (to, to_stylus)) = (vertices[segment - 1], vertices[segment]);
+            let span = ends[segment] - ends[segment - 1];
+            let t = if span > 0.0 {
+                ((distance - ends[segment - 1]) / span).clamp(0.0, 1.0)
+            } else {
+                1.0
+            };
+            let point = (from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t);
+            let stylus = match (from_stylus, to_stylus) {
+                (Some(from), Some(to)) => Some(from.lerp(&to, t)),
+                (from, _) => from,
+            };
+
+            self._mov_to(point, self.width(stylus)).await?;
+            self.set_current_state(self.current_state.with_position(point));
+        }
 
-        // osc::send_packet(Self::ON_MOVING, vec![OscType::Bool(true)])?;
+        Ok(())
+    }
 
-        // TODO: Check if the position is reached
+    async fn pen_up(&mut self) -> Result<()> {
+        if let PenState::Drawing(x, y, _) = self.current_state {
+            self.lift().await?;
+            self.set_current_state(PenState::Idle(x, y));
+        }
+        Ok(())
+    }
 
+    async fn pen_down(&mut self, stylus: Stylus) -> Result<()> {
+        if let PenState::Idle(x, y) = self.current_state {
+            self.draw(Some(stylus)).await?;
+            self.set_current_state(PenState::Drawing(x, y, stylus));
+        }
         Ok(())
     }
 
-    async fn mov(&self) -> Result<()> {
-        let target_posiotion = if let Some(target_state) = self.target_state {
-            match target_state {
-                PenState::Idle(x, y) => (x, y),
-                PenState::Drawing(x, y) => (x, y),
+    async fn run(&mut self, command: PenCommand, ticker: &mut tokio::time::Interval) -> Result<()> {
+        match command {
+            PenCommand::MoveTo(..) | PenCommand::LineTo(..) => {
+                self.run_path(&[command], ticker).await?
+            }
+            PenCommand::PenUp => self.pen_up().await?,
+            PenCommand::PenDown(stylus) => {
+                self.enable().await?;
+                self.pen_down(stylus).await?;
             }
-        } else {
+            PenCommand::SetColor(color) => self.set_color(color),
+            PenCommand::Clear => self.clear().await?,
+            PenCommand::Wait(duration) => tokio::time::sleep(duration).await,
+        }
+        Ok(())
+    }
+
+    /// Move along the moves continuing each other, lowering the pen for the lines.
+    async fn run_path(
+        &mut self,
+        commands: &[PenCommand],
+        ticker: &mut tokio::time::Interval,
+    ) -> Result<()> {
+        let path: Vec<_> = commands
+            .iter()
+            .filter_map(|command| match *command {
+                PenCommand::MoveTo(x, y) => Some(((x, y), None)),
+                PenCommand::LineTo(x, y, stylus) => Some(((x, y), Some(stylus))),
+                _ => None,
+            })
+            .collect();
+
+        self.enable().await?;
+        match path.first() {
+            Some((_, Some(stylus))) => self.pen_down(*stylus).await?,
+            Some((_, None)) => self.pen_up().await?,
+            None => return Ok(()),
+        }
+        self.move_along(&path, ticker).await?;
+
+        if let Some(&((x, y), Some(stylus))) = path.last() {
+            self.set_current_state(PenState::Drawing(x, y, stylus));
+        }
+        Ok(())
+    }
+
+    /// Lift the pen and send the reset values, before the session stops.
+    pub async fn shutdown(&mut self) -> Result<()> {
+        // Lift even if it looks lifted, since the command may have stopped while lowering it.
+        let (x, y) = self.current_state.position();
+        self.lift().await?;
+        self.set_current_state(PenState::Idle(x, y));
+
+        if !self.reset.enabled {
             return Ok(());
-        };
-        // let delta = self.calc_delta();
+        }
 
-        log::info!("Is changing the state into: {:?}", self.target_state);
+        self.send_position(self.reset.position, None).await?;
 
-        self._mov_to(target_posiotion).await?;
-        log::info!("Has changed the state into: {:?}", self.target_state);
+        if self.reset.disable_pen && self.enabled {
+            self.send_channel(self.profile.enabled.as_ref(), |binding| {
+                binding.switch(false)
+            })
+            .await?;
+            self.enabled = false;
+        }
 
         Ok(())
     }
 
-    pub async fn eval(self) {
-        let mut newer_handler = self;
+    /// Execute the command, logging the failure not to stop the following ones.
+    ///
+    /// The samples along the moves are sent at the ticks.
+    pub async fn execute(&mut self, command: PenCommand, ticker: &mut tokio::time::Interval) {
+        log::trace!("Is executing: {:?}", command);
 
-        if self.target_state == Some(newer_handler.current_state) {
-            return;
+        if let Err(e) = self.run(command, ticker).await {
+            log::error!("Failed to execute {:?} with: {}", command, e);
         }
+    }
 
-        match self.mov().await {
-            Ok(_) => {
-                if let Some(target_state) = self.target_state {
-                    newer_handler.set_current_state(target_state);
+    /// Execute the commands, moving along the moves continuing each other as one path.
+    ///
+    /// The pointer samples are usually closer than a tick's travel,
+    /// so moving along them one by one would fall behind the pointer.
+    pub async fn execute_path(
+        &mut self,
+        commands: &[PenCommand],
+        ticker: &mut tokio::time::Interval,
+    ) {
+        match commands {
+            [] => {}
+            [command] => self.execute(*command, ticker).await,
+            [first, ..] if commands.windows(2).all(|pair| pair[1].continues(&pair[0])) => {
+                log::trace!("Is executing {} moves from {:?}", commands.len(), first);
+
+                if let Err(e) = self.run_path(commands, ticker).await {
+                    log::error!("Failed to execute {} moves with: {}", commands.len(), e);
                 }
             }
-            Err(e) => {
-                log::error!("Failed to move with: {}", e);
+            _ => {
+                for command in commands {
+                    self.execute(*command, ticker).await;
+                }
             }
         }
     }
 }
 
-/*
-
-    fn mov(&mut self) -> Result<()> {
-        let delta = self.calc_delta();
-
-        let time = (delta.0.powi(2) + delta.1.powi(2)).sqrt() / self.speed;
-
-        log::info!("Is moving to target position: {:?}", self.target_state);
-        log::info!("Time: {:?}", time);
-
-        loop {
-            if let Some(task) = self.tasks.pop_front() {
-                match task {
-                    MoveState::Right(time) => {
-                        self._mov_to(Self::RIGHT, time)?;
-                    }
-                    MoveState::Left(time) => {
-                        self._mov_to(Self::LEFT, time)?;
-                    }
-                    MoveState::Up(time) => {
-                        self._mov_to(Self::UP, time)?;
-                    }
-                    MoveState::Down(time) => {
-                        self._mov_to(Self::DOWN, time)?;
-                    }
-                }
-            } else {
-                break;
-            }
+/// The message of the argument to the channel, only if the avatar has it.
+fn channel_message(
+    binding: Option<&ParameterBinding>,
+    arg: impl FnOnce(&ParameterBinding) -> OscType,
+) -> Option<(&str, Vec<OscType>)> {
+    binding.map(|binding| (binding.addr.as_str(), vec![arg(binding)]))
+}
+
+/// Send the command to the pen of the session started by [`osc::start_osc`], waiting while the queue is full.
+pub async fn send_command(command: PenCommand) -> Result<()> {
+    osc::Session::current()
+        .ok_or(VrcCanvasError::NotInitialized("PenHandler"))?
+        .pen()
+        .commands()
+        .send(command)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osc::OscConfig;
+    use std::time::Duration;
+
+    /// Bind the ports on the loopback, returning the socket standing in for VRChat.
+    fn loopback_config() -> (OscConfig, std::net::UdpSocket) {
+        let vrchat = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let out_port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = OscConfig {
+            host: "127.0.0.1".to_string(),
+            in_port: vrchat.local_addr().unwrap().port(),
+            out_port,
+            inbound: true,
+            ..Default::default()
+        };
+
+        (config, vrchat)
+    }
+
+    /// Report the parameter value as VRChat does when it changes.
+    fn report(vrchat: &std::net::UdpSocket, config: &OscConfig, addr: &str, value: f32) {
+        let packet = rosc::OscPacket::Message(rosc::OscMessage {
+            addr: config.parameter_addr(addr),
+            args: vec![OscType::Float(value)],
+        });
+        let encoded = rosc::encoder::encode(&packet).unwrap();
+        vrchat
+            .send_to(&encoded, ("127.0.0.1", config.out_port))
+            .unwrap();
+    }
+
+    fn feedback_handler(osc: Arc<OscHandler>, state: PenState) -> PenHandler {
+        let mut handler = PenHandler::new(osc, state);
+        handler.set_feedback(FeedbackConfig {
+            enabled: true,
+            timeout: Duration::from_millis(100),
+            max_retries: 0,
+            ..Default::default()
+        });
+        handler
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn feedback_starts_from_last_values() {
+        let (config, vrchat) = loopback_config();
+        let osc = Arc::new(OscHandler::new(config.clone()).unwrap());
+
+        // The center of the canvas, which VRChat has reported before the session moves the pen.
+        report(&vrchat, &config, "/Pen_X", 0.0);
+        report(&vrchat, &config, "/Pen_Y", 0.0);
+        osc.expect_parameter_with("/Pen_Y", |_| true, Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        let mut handler = feedback_handler(osc.clone(), PenState::Idle(0.5, 0.5));
+        let mut acks = handler.acks().subscribe();
+        let mut ticker = tokio::time::interval(SAMPLE_INTERVAL_DEFAULT);
+        handler
+            .execute(PenCommand::MoveTo(0.5, 0.5), &mut ticker)
+            .await;
+
+        let ack = acks.try_recv().unwrap();
+        assert!(ack.reached);
+        assert_eq!(ack.attempts, 1);
+        osc.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unreached_move_continues() {
+        let (config, _vrchat) = loopback_config();
+        let osc = Arc::new(OscHandler::new(config).unwrap());
+
+        let mut handler = feedback_handler(osc.clone(), PenState::Idle(0.5, 0.5));
+        let mut acks = handler.acks().subscribe();
+        let mut ticker = tokio::time::interval(SAMPLE_INTERVAL_DEFAULT);
+        handler
+            .execute(PenCommand::MoveTo(0.6, 0.5), &mut ticker)
+            .await;
+
+        // VRChat has never reported, but the pen is still moved to the end.
+        let ack = acks.try_recv().unwrap();
+        assert!(!ack.reached);
+        assert_eq!(handler.current_state, PenState::Idle(0.6, 0.5));
+        osc.stop().await;
+    }
+
+    #[tokio::test]
+    async fn queued_lines_move_as_one_path() {
+        let (config, _vrchat) = loopback_config();
+        let osc = Arc::new(
+            OscHandler::new(OscConfig {
+                inbound: false,
+                ..config
+            })
+            .unwrap(),
+        );
+        let settings = PenSettings {
+            motion: MotionProfile {
+                max_velocity: 1.0,
+                ..Default::default()
+            },
+            settle: SettleDelays {
+                pen_down: Duration::ZERO,
+                pen_up: Duration::ZERO,
+            },
+            ..Default::default()
+        };
+        let tick_rate = settings.tick_rate;
+        let pen = actor::spawn(PenHandler::new(osc, PenState::Idle(0.0, 0.5)), settings);
+        let mut states = pen.subscribe_state();
+
+        // The samples of a 1000 Hz pointer, much closer than a tick's travel.
+        let (count, step) = (100, 0.005);
+        let started = tokio::time::Instant::now();
+        for i in 1..=count {
+            let x = step * i as f32;
+            pen.commands()
+                .try_send(PenCommand::LineTo(x, 0.5, Stylus::default()))
+                .unwrap();
         }
 
-        log::info!("Has moved to target position: {:?}", self.target_state);
+        let end = step * count as f32;
+        while !matches!(*states.borrow_and_update(), PenState::Drawing(x, _, _) if x == end) {
+            states.changed().await.unwrap();
+        }
 
-        Ok(())
+        // 0.5 at the velocity of 1.0 takes 0.5 s, while a tick each would take 2 s.
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_secs_f32(end) - tick_rate,
+            "{:?}",
+            elapsed
+        );
+        assert!(elapsed < tick_rate * count / 2, "{:?}", elapsed);
+        pen.shutdown().await;
     }
-*/
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn draw_sends_one_bundle() {
+        let (config, vrchat) = loopback_config();
+        vrchat
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let osc = Arc::new(
+            OscHandler::new(OscConfig {
+                inbound: false,
+                ..config.clone()
+            })
+            .unwrap(),
+        );
+
+        let mut handler = PenHandler::new(osc, PenState::Idle(0.5, 0.5));
+        let mut ticker = tokio::time::interval(SAMPLE_INTERVAL_DEFAULT);
+        handler
+            .execute(PenCommand::PenDown(Stylus::default()), &mut ticker)
+            .await;
+
+        let mut buf = [0u8; rosc::decoder::MTU];
+        let packets: Vec<_> = (0..2)
+            .map(|_| {
+                let size = vrchat.recv(&mut buf).unwrap();
+                rosc::decoder::decode_udp(&buf[..size]).unwrap().1
+            })
+            .collect();
+
+        // Enabling the pen comes first, then the stroke settings with lowering it.
+        let bundle = match &packets[1] {
+            rosc::OscPacket::Bundle(bundle) => bundle,
+            packet => panic!("Not a bundle: {:?}", packet),
+        };
+        let addrs: Vec<_> = bundle
+            .content
+            .iter()
+            .map(|packet| match packet {
+                rosc::OscPacket::Message(msg) => msg.addr.clone(),
+                packet => panic!("Not a message: {:?}", packet),
+            })
+            .collect();
+        let profile = AvatarProfile::default();
+        for binding in [
+            &profile.color,
+            &profile.eraser,
+            &profile.width,
+            &profile.pen_down,
+        ] {
+            let addr = config.parameter_addr(&binding.as_ref().unwrap().addr);
+            assert!(addrs.contains(&addr), "{} isn't in {:?}", addr, addrs);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::time::{Interval, MissedTickBehavior};
+
+use super::{
+    command::{self, PenCommand, PenCommandReceiver, PenCommandSender, PEN_COMMANDS_CAPACITY},
+    feedback::MoveAck,
+    PenHandler, PenSettings, PenState,
+};
+
+/// The shortest tick rate, since the interval can't tick at zero.
+const TICK_RATE_MIN: Duration = Duration::from_millis(1);
+
+/// How long to wait for the pen to be lifted and reset when stopping.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The handle to the pen actor, which owns the pen and executes the commands in order.
+#[derive(Debug, Clone)]
+pub struct PenActorHandle {
+    commands: PenCommandSender,
+    settings: Arc<watch::Sender<PenSettings>>,
+    states: watch::Receiver<PenState>,
+    acks: broadcast::Sender<MoveAck>,
+    stop: mpsc::Sender<oneshot::Sender<()>>,
+}
+
+impl PenActorHandle {
+    pub fn commands(&self) -> &PenCommandSender {
+        &self.commands
+    }
+
+    /// Replace the settings, which the actor applies before the next command.
+    pub fn update_settings(&self, settings: PenSettings) {
+        self.settings.send_replace(settings);
+    }
+
+    /// Subscribe the state of the pen, updated at every sample sent to the avatar.
+    pub fn subscribe_state(&self) -> watch::Receiver<PenState> {
+        self.states.clone()
+    }
+
+    /// Subscribe the acknowledgements of the moves confirmed with the feedback.
+    pub fn subscribe_acks(&self) -> broadcast::Receiver<MoveAck> {
+        self.acks.subscribe()
+    }
+
+    /// Stop the actor, dropping the commands queued after lifting the pen and sending the reset values.
+    ///
+    /// Returns when the actor has stopped, or [`SHUTDOWN_TIMEOUT`] has passed.
+    pub async fn shutdown(&self) {
+        let (done, stopped) = oneshot::channel();
+
+        // It only fails when the actor has already stopped.
+        if self.stop.send(done).await.is_ok()
+            && tokio::time::timeout(SHUTDOWN_TIMEOUT, stopped)
+                .await
+                .is_err()
+        {
+            log::warn!("Timed out while stopping the pen");
+        }
+    }
+}
+
+struct PenActor {
+    handler: PenHandler,
+    commands: PenCommandReceiver,
+    settings: watch::Receiver<PenSettings>,
+    ticker: Interval,
+    stop: mpsc::Receiver<oneshot::Sender<()>>,
+    /// The command received after the path, executed next.
+    pending: Option<PenCommand>,
+}
+
+/// Spawn the actor owning the pen, which runs until every handle is dropped.
+pub fn spawn(handler: PenHandler, settings: PenSettings) -> PenActorHandle {
+    let (commands, receiver) = command::channel(PEN_COMMANDS_CAPACITY);
+    let tick_rate = settings.tick_rate;
+    let (settings_sender, settings_receiver) = watch::channel(settings);
+    let states = handler.subscribe_state();
+    let acks = handler.acks();
+    let (stop_sender, stop_receiver) = mpsc::channel(1);
+
+    let actor = PenActor {
+        handler,
+        commands: receiver,
+        settings: settings_receiver,
+        ticker: ticker(tick_rate),
+        stop: stop_receiver,
+        pending: None,
+    };
+    tokio::spawn(actor.run());
+
+    PenActorHandle {
+        commands,
+        settings: Arc::new(settings_sender),
+        states,
+        acks,
+        stop: stop_sender,
+    }
+}
+
+fn ticker(tick_rate: Duration) -> Interval {
+    let mut ticker = tokio::time::interval(tick_rate.max(TICK_RATE_MIN));
+    // Don't burst the samples missed while waiting for the commands or the settle delays.
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker
+}
+
+impl PenActor {
+    fn apply_settings(&mut self) {
+        let settings = self.settings.borrow_and_update().clone();
+
+        if settings.tick_rate.max(TICK_RATE_MIN) != self.ticker.period() {
+            self.ticker = ticker(settings.tick_rate);
+        }
+        self.handler.apply_settings(&settings);
+    }
+
+    /// Collect the moves queued right after the command, which continue it in the same pen state.
+    fn collect_path(&mut self, command: PenCommand) -> Vec<PenCommand> {
+        let mut path = vec![command];
+
+        while let Some(next) = self.commands.try_recv() {
+            if next.continues(&path[path.len() - 1]) {
+                path.push(next);
+            } else {
+                self.pending = Some(next);
+                break;
+            }
+        }
+
+        path
+    }
+
+    async fn run(mut self) {
+        self.apply_settings();
+
+        let done = loop {
+            let command = match self.pending.take() {
+                Some(command) => command,
+                None => tokio::select! {
+                    biased;
+                    done = self.stop.recv() => break done,
+                    changed = self.settings.changed() => {
+                        match changed {
+                            Ok(()) => self.apply_settings(),
+                            Err(_) => break None,
+                        }
+                        continue;
+                    }
+                    command = self.commands.recv() => match command {
+                        Some(command) => command,
+                        None => break None,
+                    },
+                },
+            };
+
+            if self.settings.has_changed().unwrap_or(false) {
+                self.apply_settings();
+            }
+            // Move along the queued samples at once, not to spend a tick on each of them.
+            let path = self.collect_path(command);
+
+            // Stop even in the middle of a stroke, which is lifted right after.
+            tokio::select! {
+                biased;
+                done = self.stop.recv() => break done,
+                _ = self.handler.execute_path(&path, &mut self.ticker) => {}
+            }
+        };
+
+        if let Err(e) = self.handler.shutdown().await {
+            log::error!("Failed to reset the pen with: {}", e);
+        }
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
+
+        log::info!("The pen actor has stopped");
+    }
+}
@@ -0,0 +1,90 @@
+/// The stylus input at a point of the stroke.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stylus {
+    /// From 0.0 to 1.0.
+    pub pressure: f32,
+    /// The tilt away from the perpendicular, from 0.0 to 1.0, when the device reports it.
+    pub tilt: Option<f32>,
+    /// Whether erasing with the eraser end or the barrel button.
+    pub eraser: bool,
+}
+
+impl Default for Stylus {
+    fn default() -> Self {
+        Self {
+            pressure: 1.0,
+            tilt: None,
+            eraser: false,
+        }
+    }
+}
+
+impl Stylus {
+    /// Interpolate towards the other input, so the width tapers along the segment.
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        let tilt = match (self.tilt, other.tilt) {
+            (Some(from), Some(to)) => Some(from + (to - from) * t),
+            (_, tilt) => tilt,
+        };
+
+        Self {
+            pressure: self.pressure + (other.pressure - self.pressure) * t,
+            tilt,
+            eraser: other.eraser,
+        }
+    }
+}
+
+/// How the stylus input is mapped to the brush width parameter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BrushConfig {
+    pub enabled: bool,
+    pub min_width: f32,
+    pub max_width: f32,
+    /// The exponent of the response curve, where above 1.0 needs harder presses to widen the brush.
+    pub gamma: f32,
+    /// How much the full tilt widens the brush, in proportion to the width.
+    pub tilt_influence: f32,
+    /// Whether to simulate the pressure from the velocity for the devices without it, such as mice.
+    pub simulate_pressure: bool,
+    /// The velocity at which the simulated pressure falls to zero, in canvas sizes per second.
+    pub simulated_velocity: f32,
+}
+
+impl Default for BrushConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_width: 0.1,
+            max_width: 1.0,
+            gamma: 1.0,
+            tilt_influence: 0.5,
+            simulate_pressure: true,
+            simulated_velocity: 3.0,
+        }
+    }
+}
+
+impl BrushConfig {
+    /// Map the stylus input to the width parameter from 0.0 to 1.0.
+    pub fn width(&self, stylus: Stylus) -> f32 {
+        let pressure = stylus.pressure.clamp(0.0, 1.0);
+        let response = if self.gamma > 0.0 && self.gamma.is_finite() {
+            pressure.powf(self.gamma)
+        } else {
+            pressure
+        };
+        let tilt = 1.0 + self.tilt_influence.max(0.0) * stylus.tilt.unwrap_or(0.0).clamp(0.0, 1.0);
+
+        ((self.min_width + (self.max_width - self.min_width) * response) * tilt).clamp(0.0, 1.0)
+    }
+
+    /// Simulate the pressure from the velocity, which thins the brush as it moves faster like ink.
+    pub fn simulated_pressure(&self, velocity: f32) -> f32 {
+        if !(self.simulated_velocity > 0.0 && self.simulated_velocity.is_finite()) {
+            return 1.0;
+        }
+
+        (1.0 - velocity / self.simulated_velocity).clamp(0.0, 1.0)
+    }
+}
@@ -0,0 +1,44 @@
+use serde::{Deserialize, Serialize};
+
+/// How the stroke color is sent to the avatar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorMode {
+    /// The index in the palette as an int parameter.
+    #[default]
+    PaletteIndex,
+    /// The hue, the saturation and the value as float parameters from 0.0 to 1.0.
+    Hsv,
+}
+
+impl ColorMode {
+    pub const ALL: [Self; 2] = [Self::PaletteIndex, Self::Hsv];
+}
+
+/// The color of the strokes, chosen from the palette.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PenColor {
+    pub index: i32,
+    pub hsv: (f32, f32, f32),
+}
+
+impl Default for PenColor {
+    fn default() -> Self {
+        Self {
+            index: 0,
+            hsv: (0.0, 0.0, 1.0),
+        }
+    }
+}
+
+/// Whether the stroke color is sent, in the mode of the avatar profile.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorConfig {
+    pub enabled: bool,
+}
+
+impl Default for ColorConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
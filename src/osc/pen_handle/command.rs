@@ -0,0 +1,240 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use super::{brush::Stylus, color::PenColor, PenState};
+use crate::osc::{Result, VrcCanvasError};
+
+/// The number of commands queued before the senders wait for the pen to catch up.
+pub const PEN_COMMANDS_CAPACITY: usize = 1024;
+
+/// A command to the pen, executed in order.
+///
+/// The positions are normalized in the canvas.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PenCommand {
+    /// Travel to the position, lifting the pen if it is lowered.
+    MoveTo(f32, f32),
+    /// Draw a line to the position, lowering the pen if it is lifted.
+    LineTo(f32, f32, Stylus),
+    PenUp,
+    PenDown(Stylus),
+    SetColor(PenColor),
+    /// Clear the drawing on the avatar.
+    Clear,
+    Wait(Duration),
+}
+
+impl PenCommand {
+    /// The commands to change the pen from the state into the other.
+    pub fn between(from: Option<PenState>, to: PenState) -> Vec<Self> {
+        match (from, to) {
+            (Some(PenState::Drawing(..)), PenState::Idle(x, y)) => {
+                vec![Self::PenUp, Self::MoveTo(x, y)]
+            }
+            (_, PenState::Idle(x, y)) => vec![Self::MoveTo(x, y)],
+            // Start another stroke when switching between drawing and erasing.
+            (Some(PenState::Drawing(_, _, from)), PenState::Drawing(x, y, stylus))
+                if from.eraser != stylus.eraser =>
+            {
+                vec![Self::PenUp, Self::MoveTo(x, y), Self::PenDown(stylus)]
+            }
+            (Some(PenState::Drawing(..)), PenState::Drawing(x, y, stylus)) => {
+                vec![Self::LineTo(x, y, stylus)]
+            }
+            (_, PenState::Drawing(x, y, stylus)) => vec![Self::MoveTo(x, y), Self::PenDown(stylus)],
+        }
+    }
+
+    /// Whether the command continues the move before it in the same pen state,
+    /// so both can be moved along as one path.
+    pub fn continues(&self, previous: &Self) -> bool {
+        match (previous, self) {
+            (Self::MoveTo(..), Self::MoveTo(..)) => true,
+            (Self::LineTo(_, _, previous), Self::LineTo(_, _, stylus)) => {
+                previous.eraser == stylus.eraser
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Create the bounded queue of the pen commands.
+pub fn channel(capacity: usize) -> (PenCommandSender, PenCommandReceiver) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let depth = Arc::new(AtomicUsize::new(0));
+
+    (
+        PenCommandSender {
+            sender,
+            depth: depth.clone(),
+        },
+        PenCommandReceiver { receiver, depth },
+    )
+}
+
+/// Sends the commands to the pen, counting the commands queued.
+#[derive(Debug, Clone)]
+pub struct PenCommandSender {
+    sender: mpsc::Sender<PenCommand>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl PenCommandSender {
+    /// Send the command, waiting while the queue is full.
+    pub async fn send(&self, command: PenCommand) -> Result<()> {
+        // Count it first, not to underflow when it is received right away.
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        if self.sender.send(command).await.is_err() {
+            self.depth.fetch_sub(1, Ordering::SeqCst);
+            return Err(VrcCanvasError::PenStopped);
+        }
+        Ok(())
+    }
+
+    /// Send the command without waiting, giving it back when the queue is full or closed.
+    pub fn try_send(&self, command: PenCommand) -> Result<(), PenCommand> {
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        self.sender.try_send(command).map_err(|e| {
+            self.depth.fetch_sub(1, Ordering::SeqCst);
+            match e {
+                mpsc::error::TrySendError::Full(command) => command,
+                mpsc::error::TrySendError::Closed(command) => command,
+            }
+        })
+    }
+
+    /// The number of the commands waiting in the queue.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+}
+
+/// Receives the commands in the order they are sent.
+#[derive(Debug)]
+pub struct PenCommandReceiver {
+    receiver: mpsc::Receiver<PenCommand>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl PenCommandReceiver {
+    pub async fn recv(&mut self) -> Option<PenCommand> {
+        let command = self.receiver.recv().await?;
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+        Some(command)
+    }
+
+    /// Receive the command already queued, without waiting.
+    pub fn try_recv(&mut self) -> Option<PenCommand> {
+        let command = self.receiver.try_recv().ok()?;
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+        Some(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEN: Stylus = Stylus {
+        pressure: 1.0,
+        tilt: None,
+        eraser: false,
+    };
+    const ERASER: Stylus = Stylus {
+        pressure: 1.0,
+        tilt: None,
+        eraser: true,
+    };
+
+    #[test]
+    fn idle_to_idle_moves() {
+        assert_eq!(
+            PenCommand::between(Some(PenState::Idle(0.0, 0.0)), PenState::Idle(0.5, 0.5)),
+            vec![PenCommand::MoveTo(0.5, 0.5)]
+        );
+        assert_eq!(
+            PenCommand::between(None, PenState::Idle(0.5, 0.5)),
+            vec![PenCommand::MoveTo(0.5, 0.5)]
+        );
+    }
+
+    #[test]
+    fn idle_to_drawing_lowers_pen() {
+        assert_eq!(
+            PenCommand::between(
+                Some(PenState::Idle(0.0, 0.0)),
+                PenState::Drawing(0.5, 0.5, PEN)
+            ),
+            vec![PenCommand::MoveTo(0.5, 0.5), PenCommand::PenDown(PEN)]
+        );
+        assert_eq!(
+            PenCommand::between(None, PenState::Drawing(0.5, 0.5, PEN)),
+            vec![PenCommand::MoveTo(0.5, 0.5), PenCommand::PenDown(PEN)]
+        );
+    }
+
+    #[test]
+    fn drawing_to_idle_lifts_pen() {
+        assert_eq!(
+            PenCommand::between(
+                Some(PenState::Drawing(0.0, 0.0, PEN)),
+                PenState::Idle(0.5, 0.5)
+            ),
+            vec![PenCommand::PenUp, PenCommand::MoveTo(0.5, 0.5)]
+        );
+    }
+
+    #[test]
+    fn drawing_to_drawing_draws_line() {
+        assert_eq!(
+            PenCommand::between(
+                Some(PenState::Drawing(0.0, 0.0, PEN)),
+                PenState::Drawing(0.5, 0.5, PEN)
+            ),
+            vec![PenCommand::LineTo(0.5, 0.5, PEN)]
+        );
+    }
+
+    #[test]
+    fn moves_continue_in_same_state() {
+        let line = |stylus| PenCommand::LineTo(0.5, 0.5, stylus);
+
+        assert!(PenCommand::MoveTo(0.5, 0.5).continues(&PenCommand::MoveTo(0.0, 0.0)));
+        assert!(line(PEN).continues(&line(PEN)));
+        assert!(!line(ERASER).continues(&line(PEN)));
+        assert!(!line(PEN).continues(&PenCommand::MoveTo(0.0, 0.0)));
+        assert!(!PenCommand::MoveTo(0.5, 0.5).continues(&line(PEN)));
+        assert!(!PenCommand::PenUp.continues(&PenCommand::PenUp));
+    }
+
+    #[test]
+    fn switching_to_eraser_starts_another_stroke() {
+        let expected = |stylus| {
+            vec![
+                PenCommand::PenUp,
+                PenCommand::MoveTo(0.5, 0.5),
+                PenCommand::PenDown(stylus),
+            ]
+        };
+
+        assert_eq!(
+            PenCommand::between(
+                Some(PenState::Drawing(0.0, 0.0, PEN)),
+                PenState::Drawing(0.5, 0.5, ERASER)
+            ),
+            expected(ERASER)
+        );
+        assert_eq!(
+            PenCommand::between(
+                Some(PenState::Drawing(0.0, 0.0, ERASER)),
+                PenState::Drawing(0.5, 0.5, PEN)
+            ),
+            expected(PEN)
+        );
+    }
+}
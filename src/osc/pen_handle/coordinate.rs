@@ -0,0 +1,250 @@
+/// A point normalized in the canvas, independent of the GUI.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Point {
+    pub const fn new(x: f32, y: f32) -> Self {
+        Self { x, y }
+    }
+}
+
+impl From<(f32, f32)> for Point {
+    fn from((x, y): (f32, f32)) -> Self {
+        Self { x, y }
+    }
+}
+
+impl From<Point> for (f32, f32) {
+    fn from(point: Point) -> Self {
+        (point.x, point.y)
+    }
+}
+
+/// How the pen position on the canvas is mapped to the x and y channels of the pen.
+///
+/// The position is normalized so that the canvas spans from 0.0 to 1.0 on both axes,
+/// hence it doesn't depend on the canvas size.
+/// The ranges of the avatar parameters are up to the [`super::profile::AvatarProfile`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoordinateMapping {
+    pub invert_x: bool,
+    pub invert_y: bool,
+    /// Whether to keep the shorter axis from being stretched to the full range.
+    pub preserve_aspect_ratio: bool,
+    /// The aspect ratio of the canvas as width and height.
+    pub aspect_ratio: (f32, f32),
+}
+
+impl Default for CoordinateMapping {
+    fn default() -> Self {
+        Self {
+            invert_x: false,
+            invert_y: false,
+            preserve_aspect_ratio: true,
+            aspect_ratio: (16.0, 9.0),
+        }
+    }
+}
+
+impl CoordinateMapping {
+    /// Map the normalized position on the canvas to the logical values from 0.0 to 1.0.
+    pub fn to_logical(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        let (mut x, mut y) = (x.clamp(0.0, 1.0), y.clamp(0.0, 1.0));
+
+        let (width, height) = self.aspect_ratio;
+        if self.preserve_aspect_ratio && width > 0.0 && height > 0.0 {
+            // Center the shorter axis, scaling it as much as the longer one.
+            if width > height {
+                y = 0.5 + (y - 0.5) * height / width;
+            } else {
+                x = 0.5 + (x - 0.5) * width / height;
+            }
+        }
+
+        if self.invert_x {
+            x = 1.0 - x;
+        }
+        if self.invert_y {
+            y = 1.0 - y;
+        }
+
+        (x, y)
+    }
+}
+
+/// A segment clipped to the canvas.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClippedSegment {
+    pub from: (f32, f32),
+    pub to: (f32, f32),
+    /// Whether the segment has entered the canvas from the outside.
+    pub entered: bool,
+    /// Whether the segment has left the canvas to the outside.
+    pub exited: bool,
+}
+
+/// Clip the segment to the canvas spanning from 0.0 to 1.0 on both axes.
+///
+/// Returns `None` when the segment doesn't cross the canvas at all.
+pub fn clip_segment(from: (f32, f32), to: (f32, f32)) -> Option<ClippedSegment> {
+    let delta = (to.0 - from.0, to.1 - from.1);
+    let (mut t_from, mut t_to) = (0.0f32, 1.0f32);
+
+    // Liang-Barsky against each edge: the left, the right, the top and the bottom.
+    for (p, q) in [
+        (-delta.0, from.0),
+        (delta.0, 1.0 - from.0),
+        (-delta.1, from.1),
+        (delta.1, 1.0 - from.1),
+    ] {
+        if p == 0.0 {
+            // Parallel to the edge, so it's either entirely inside or outside of it.
+            if q < 0.0 {
+                return None;
+            }
+            continue;
+        }
+
+        let t = q / p;
+        if p < 0.0 {
+            t_from = t_from.max(t);
+        } else {
+            t_to = t_to.min(t);
+        }
+
+        if t_from > t_to {
+            return None;
+        }
+    }
+
+    let at = |t: f32| (from.0 + delta.0 * t, from.1 + delta.1 * t);
+
+    Some(ClippedSegment {
+        from: at(t_from),
+        to: at(t_to),
+        entered: t_from > 0.0,
+        exited: t_to < 1.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: (f32, f32), expected: (f32, f32)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-5 && (actual.1 - expected.1).abs() < 1e-5,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn to_logical_centers_shorter_axis() {
+        let mapping = CoordinateMapping {
+            aspect_ratio: (16.0, 9.0),
+            ..Default::default()
+        };
+
+        assert_near(mapping.to_logical((0.5, 0.5)), (0.5, 0.5));
+        assert_near(
+            mapping.to_logical((0.0, 0.0)),
+            (0.0, 0.5 - 0.5 * 9.0 / 16.0),
+        );
+        assert_near(
+            mapping.to_logical((1.0, 1.0)),
+            (1.0, 0.5 + 0.5 * 9.0 / 16.0),
+        );
+
+        let portrait = CoordinateMapping {
+            aspect_ratio: (9.0, 16.0),
+            ..Default::default()
+        };
+        assert_near(
+            portrait.to_logical((0.0, 1.0)),
+            (0.5 - 0.5 * 9.0 / 16.0, 1.0),
+        );
+    }
+
+    #[test]
+    fn to_logical_stretches_without_aspect_ratio() {
+        let mapping = CoordinateMapping {
+            preserve_aspect_ratio: false,
+            ..Default::default()
+        };
+
+        assert_near(mapping.to_logical((0.25, 0.75)), (0.25, 0.75));
+        // Clamped to the canvas.
+        assert_near(mapping.to_logical((-1.0, 2.0)), (0.0, 1.0));
+    }
+
+    #[test]
+    fn to_logical_inverts() {
+        let mapping = CoordinateMapping {
+            invert_x: true,
+            invert_y: true,
+            preserve_aspect_ratio: false,
+            ..Default::default()
+        };
+
+        assert_near(mapping.to_logical((0.25, 0.75)), (0.75, 0.25));
+    }
+
+    #[test]
+    fn clip_inside_keeps_segment() {
+        let clipped = clip_segment((0.2, 0.2), (0.8, 0.6)).unwrap();
+
+        assert_near(clipped.from, (0.2, 0.2));
+        assert_near(clipped.to, (0.8, 0.6));
+        assert!(!clipped.entered && !clipped.exited);
+    }
+
+    #[test]
+    fn clip_exiting_segment() {
+        let clipped = clip_segment((0.5, 0.5), (1.5, 0.5)).unwrap();
+
+        assert_near(clipped.to, (1.0, 0.5));
+        assert!(!clipped.entered && clipped.exited);
+    }
+
+    #[test]
+    fn clip_entering_segment() {
+        let clipped = clip_segment((0.5, -0.5), (0.5, 0.5)).unwrap();
+
+        assert_near(clipped.from, (0.5, 0.0));
+        assert!(clipped.entered && !clipped.exited);
+    }
+
+    #[test]
+    fn clip_crossing_segment() {
+        let clipped = clip_segment((-0.5, 0.5), (1.5, 0.5)).unwrap();
+
+        assert_near(clipped.from, (0.0, 0.5));
+        assert_near(clipped.to, (1.0, 0.5));
+        assert!(clipped.entered && clipped.exited);
+    }
+
+    #[test]
+    fn clip_outside_segment() {
+        assert_eq!(clip_segment((-0.5, -0.5), (1.5, -0.1)), None);
+        // Parallel to the edge, outside of it.
+        assert_eq!(clip_segment((1.2, 0.0), (1.2, 1.0)), None);
+    }
+
+    #[test]
+    fn stroke_leaving_and_reentering() {
+        // The segments of a stroke going out of the right edge and coming back.
+        let out = clip_segment((0.8, 0.5), (1.2, 0.5)).unwrap();
+        assert!(!out.entered && out.exited);
+
+        assert_eq!(clip_segment((1.2, 0.5), (1.3, 0.6)), None);
+
+        let back = clip_segment((1.3, 0.6), (0.9, 0.6)).unwrap();
+        assert!(back.entered && !back.exited);
+        assert_near(back.from, (1.0, 0.6));
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use crate::osc::listener::AvatarParameterUpdate;
+
+/// The number of acknowledgements kept for subscribers lagging behind.
+pub const MOVE_ACKS_CAPACITY: usize = 64;
+
+/// Settings to confirm the pen has reached the target with the position reported by VRChat.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FeedbackConfig {
+    pub enabled: bool,
+    /// The maximum difference between the sent and the reported parameter values.
+    pub tolerance: f32,
+    /// How long to wait for VRChat to report the position for each attempt.
+    pub timeout: Duration,
+    /// How many times to resend the position when it isn't reached.
+    pub max_retries: u32,
+}
+
+impl Default for FeedbackConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            tolerance: 0.01,
+            timeout: Duration::from_millis(500),
+            max_retries: 2,
+        }
+    }
+}
+
+/// The acknowledgement of a move confirmed by VRChat.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MoveAck {
+    /// The parameter values sent.
+    pub target: (f32, f32),
+    /// The last parameter values reported by VRChat.
+    pub reported: Option<(f32, f32)>,
+    /// The time from the first send to the confirmation or the give-up.
+    pub latency: Duration,
+    pub attempts: u32,
+    pub reached: bool,
+}
+
+pub(crate) fn publish(acks: &broadcast::Sender<MoveAck>, ack: MoveAck) {
+    if ack.reached {
+        log::trace!("Has reached the position: {:?}", ack);
+    } else {
+        log::warn!("Couldn't reach the position: {:?}", ack);
+    }
+
+    // It only fails when nobody subscribes.
+    let _ = acks.send(ack);
+}
+
+/// Tracks the pen position reported by VRChat.
+pub(crate) struct PositionWatcher {
+    updates: broadcast::Receiver<AvatarParameterUpdate>,
+    x_addr: String,
+    y_addr: String,
+    reported: (Option<f32>, Option<f32>),
+}
+
+impl PositionWatcher {
+    /// Start from the values reported before, since VRChat only reports the values which have changed.
+    pub fn new(
+        updates: broadcast::Receiver<AvatarParameterUpdate>,
+        x_addr: String,
+        y_addr: String,
+        reported: (Option<f32>, Option<f32>),
+    ) -> Self {
+        Self {
+            updates,
+            x_addr,
+            y_addr,
+            reported,
+        }
+    }
+
+    pub fn reported(&self) -> Option<(f32, f32)> {
+        match self.reported {
+            (Some(x), Some(y)) => Some((x, y)),
+            _ => None,
+        }
+    }
+
+    fn is_reached(&self, target: (f32, f32), tolerance: f32) -> bool {
+        self.reported().is_some_and(|(x, y)| {
+            (x - target.0).abs() <= tolerance && (y - target.1).abs() <= tolerance
+        })
+    }
+
+    /// Wait until the reported position is within the tolerance, returning whether it is reached.
+    pub async fn wait_for(
+        &mut self,
+        target: (f32, f32),
+        tolerance: f32,
+        timeout: Duration,
+    ) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        while !self.is_reached(target, tolerance) {
+            match tokio::time::timeout_at(deadline, self.updates.recv()).await {
+                Ok(Ok(update)) => {
+                    if update.addr == self.x_addr {
+                        self.reported.0 = Some(update.value.as_f32());
+                    } else if update.addr == self.y_addr {
+                        self.reported.1 = Some(update.value.as_f32());
+                    }
+                }
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => return false,
+            }
+        }
+
+        true
+    }
+}
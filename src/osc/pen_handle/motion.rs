@@ -0,0 +1,265 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// The upper bound of the samples planned for a segment.
+const MAX_SAMPLES: usize = 10_000;
+
+/// The number of integration steps per sample.
+const SUBSTEPS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MotionProfileKind {
+    /// Move at the max velocity from the start to the end.
+    #[default]
+    ConstantVelocity,
+    /// Accelerate and decelerate at the max acceleration.
+    Trapezoidal,
+    /// Accelerate and decelerate at the max acceleration, changing it at the max jerk.
+    SCurve,
+}
+
+impl MotionProfileKind {
+    pub const ALL: [Self; 3] = [Self::ConstantVelocity, Self::Trapezoidal, Self::SCurve];
+}
+
+/// How the pen moves along a segment.
+///
+/// The limits are in canvas sizes per second, so a velocity of 1.0 crosses the canvas in a second.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MotionProfile {
+    pub kind: MotionProfileKind,
+    pub max_velocity: f32,
+    pub max_acceleration: f32,
+    pub max_jerk: f32,
+}
+
+impl Default for MotionProfile {
+    fn default() -> Self {
+        Self {
+            kind: MotionProfileKind::default(),
+            max_velocity: 1.5,
+            max_acceleration: 8.0,
+            max_jerk: 80.0,
+        }
+    }
+}
+
+/// The acceleration phase of a profile, which is mirrored for the deceleration.
+#[derive(Debug, Clone, Copy)]
+struct Ramp {
+    v_peak: f32,
+    a_peak: f32,
+    /// The time spent changing the acceleration at each end of the ramp.
+    t_jerk: f32,
+    /// The time spent at the peak acceleration.
+    t_const: f32,
+}
+
+fn is_limit(value: f32) -> bool {
+    value > 0.0 && value.is_finite()
+}
+
+impl Ramp {
+    fn new(kind: MotionProfileKind, v_peak: f32, max_acceleration: f32, max_jerk: f32) -> Self {
+        let ramp = Self {
+            v_peak,
+            a_peak: 0.0,
+            t_jerk: 0.0,
+            t_const: 0.0,
+        };
+
+        match kind {
+            MotionProfileKind::ConstantVelocity => ramp,
+            _ if !is_limit(max_acceleration) => ramp,
+            MotionProfileKind::Trapezoidal => Self {
+                a_peak: max_acceleration,
+                t_const: v_peak / max_acceleration,
+                ..ramp
+            },
+            MotionProfileKind::SCurve if !is_limit(max_jerk) => Self::new(
+                MotionProfileKind::Trapezoidal,
+                v_peak,
+                max_acceleration,
+                0.0,
+            ),
+            MotionProfileKind::SCurve => {
+                if v_peak >= max_acceleration.powi(2) / max_jerk {
+                    Self {
+                        a_peak: max_acceleration,
+                        t_jerk: max_acceleration / max_jerk,
+                        t_const: v_peak / max_acceleration - max_acceleration / max_jerk,
+                        ..ramp
+                    }
+                } else {
+                    // The acceleration can't reach the max before the velocity does.
+                    Self {
+                        a_peak: (v_peak * max_jerk).sqrt(),
+                        t_jerk: (v_peak / max_jerk).sqrt(),
+                        ..ramp
+                    }
+                }
+            }
+        }
+    }
+
+    fn duration(&self) -> f32 {
+        self.t_jerk * 2.0 + self.t_const
+    }
+
+    /// The ramp is point symmetric, so the average velocity is the half of the peak.
+    fn distance(&self) -> f32 {
+        self.v_peak * self.duration() / 2.0
+    }
+
+    fn velocity(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, self.duration());
+
+        if t < self.t_jerk {
+            let jerk = self.a_peak / self.t_jerk;
+            jerk * t.powi(2) / 2.0
+        } else if t < self.t_jerk + self.t_const {
+            let v_jerk = self.a_peak * self.t_jerk / 2.0;
+            v_jerk + self.a_peak * (t - self.t_jerk)
+        } else if self.t_jerk <= 0.0 {
+            self.v_peak
+        } else {
+            let jerk = self.a_peak / self.t_jerk;
+            let remaining = self.duration() - t;
+            self.v_peak - jerk * remaining.powi(2) / 2.0
+        }
+    }
+}
+
+impl MotionProfile {
+    fn ramp(&self, v_peak: f32) -> Ramp {
+        Ramp::new(self.kind, v_peak, self.max_acceleration, self.max_jerk)
+    }
+
+    /// Plan the distances travelled along a segment of the length at every interval.
+    ///
+    /// The last distance is always the length itself.
+    pub fn plan(&self, length: f32, interval: Duration) -> Vec<f32> {
+        let dt = interval.as_secs_f32();
+        if length <= f32::EPSILON || !is_limit(self.max_velocity) || !is_limit(dt) {
+            return vec![length.max(0.0)];
+        }
+
+        let mut ramp = self.ramp(self.max_velocity);
+
+        // Lower the peak velocity until both the ramps fit in the segment.
+        if ramp.distance() * 2.0 > length {
+            let (mut low, mut high) = (0.0, self.max_velocity);
+            for _ in 0..32 {
+                let mid = (low + high) / 2.0;
+                if self.ramp(mid).distance() * 2.0 > length {
+                    high = mid;
+                } else {
+                    low = mid;
+                }
+            }
+            ramp = self.ramp(low.max(f32::EPSILON));
+        }
+
+        let t_ramp = ramp.duration();
+        let t_cruise = ((length - ramp.distance() * 2.0) / ramp.v_peak).max(0.0);
+        let total = t_ramp * 2.0 + t_cruise;
+
+        let velocity = |t: f32| {
+            if t < t_ramp {
+                ramp.velocity(t)
+            } else if t < t_ramp + t_cruise {
+                ramp.v_peak
+            } else {
+                ramp.velocity(total - t)
+            }
+        };
+
+        let substep = dt / SUBSTEPS as f32;
+        let mut distances = Vec::new();
+        let mut distance = 0.0;
+        let mut t = 0.0;
+
+        while t + dt < total && distances.len() < MAX_SAMPLES {
+            for _ in 0..SUBSTEPS {
+                // Integrate with the midpoint rule.
+                distance += velocity(t + substep / 2.0) * substep;
+                t += substep;
+            }
+            distances.push(distance.min(length));
+        }
+        distances.push(length);
+
+        distances
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(20);
+
+    fn profiles() -> impl Iterator<Item = MotionProfile> {
+        MotionProfileKind::ALL
+            .into_iter()
+            .map(|kind| MotionProfile {
+                kind,
+                ..Default::default()
+            })
+    }
+
+    #[test]
+    fn plan_ends_at_length() {
+        for profile in profiles() {
+            for length in [0.001, 0.05, 0.3, 1.0, 2.5] {
+                let distances = profile.plan(length, INTERVAL);
+                assert_eq!(distances.last(), Some(&length), "{:?}", profile.kind);
+            }
+        }
+    }
+
+    #[test]
+    fn plan_never_moves_backwards() {
+        for profile in profiles() {
+            for length in [0.05, 0.3, 1.0, 2.5] {
+                let distances = profile.plan(length, INTERVAL);
+                assert!(
+                    distances.windows(2).all(|w| w[0] <= w[1]),
+                    "{:?}: {:?}",
+                    profile.kind,
+                    distances
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn plan_steps_within_max_velocity() {
+        for profile in profiles() {
+            let max_step = profile.max_velocity * INTERVAL.as_secs_f32() * (1.0 + 1e-3);
+
+            for length in [0.05, 0.3, 1.0, 2.5] {
+                let distances = profile.plan(length, INTERVAL);
+                let mut last = 0.0;
+                for distance in distances {
+                    assert!(
+                        distance - last <= max_step,
+                        "{:?}: stepped {} over {}",
+                        profile.kind,
+                        distance - last,
+                        max_step
+                    );
+                    last = distance;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn plan_without_length_stays() {
+        assert_eq!(MotionProfile::default().plan(0.0, INTERVAL), vec![0.0]);
+    }
+}
@@ -0,0 +1,362 @@
+use std::path::{Path, PathBuf};
+
+use rosc::OscType;
+use serde::{Deserialize, Serialize};
+
+use super::{color::ColorMode, motion::MotionProfile};
+use crate::osc::{Result, VrcCanvasError};
+
+/// The type of the avatar parameter a channel is sent as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParameterType {
+    // The aliases are the names in the avatar configs written by VRChat.
+    #[serde(alias = "Bool")]
+    Bool,
+    #[serde(alias = "Int")]
+    Int,
+    #[default]
+    #[serde(alias = "Float")]
+    Float,
+}
+
+impl ParameterType {
+    /// Convert the parameter value into the type, rounding it for the ints.
+    pub fn encode(&self, value: f32) -> OscType {
+        match self {
+            Self::Bool => OscType::Bool(value >= 0.5),
+            Self::Int => OscType::Int(value.round() as i32),
+            Self::Float => OscType::Float(value),
+        }
+    }
+}
+
+/// A logical channel of the pen bound to an avatar parameter.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParameterBinding {
+    /// The address relative to the base address, such as `/Pen_X`.
+    pub addr: String,
+    #[serde(rename = "type", default)]
+    pub ty: ParameterType,
+    /// The parameter values the logical values from 0.0 to 1.0 are mapped to.
+    #[serde(default = "ParameterBinding::range_default")]
+    pub range: (f32, f32),
+    #[serde(default)]
+    pub invert: bool,
+}
+
+impl ParameterBinding {
+    fn range_default() -> (f32, f32) {
+        (0.0, 1.0)
+    }
+
+    pub fn new(addr: &str, ty: ParameterType) -> Self {
+        Self {
+            addr: addr.to_string(),
+            ty,
+            range: Self::range_default(),
+            invert: false,
+        }
+    }
+
+    pub fn with_range(self, range: (f32, f32)) -> Self {
+        Self { range, ..self }
+    }
+
+    /// Map the logical value from 0.0 to 1.0 to the parameter value.
+    pub fn map(&self, value: f32) -> f32 {
+        let value = value.clamp(0.0, 1.0);
+        let value = if self.invert { 1.0 - value } else { value };
+        let (min, max) = self.range;
+
+        min + (max - min) * value
+    }
+
+    /// The argument of the logical value from 0.0 to 1.0.
+    pub fn scaled(&self, value: f32) -> OscType {
+        self.ty.encode(self.map(value))
+    }
+
+    /// The argument of the switch, which is the either end of the range unless the parameter is a bool.
+    pub fn switch(&self, on: bool) -> OscType {
+        match self.ty {
+            ParameterType::Bool => OscType::Bool(on != self.invert),
+            _ => self.scaled(if on { 1.0 } else { 0.0 }),
+        }
+    }
+
+    /// The argument of the parameter value as is, only converted into the type.
+    pub fn raw(&self, value: f32) -> OscType {
+        self.ty.encode(value)
+    }
+}
+
+/// Which avatar parameters the logical channels of the pen are sent to.
+///
+/// Every avatar pen names its parameters differently, so the profiles are loaded from
+/// the TOML or JSON files, such as:
+///
+/// ```toml
+/// name = "My Pen"
+/// color_mode = "hsv"
+/// avatars = ["avtr_00000000-0000-0000-0000-000000000000"]
+///
+/// [x]
+/// addr = "/MyPen/X"
+/// range = [-1.0, 1.0]
+///
+/// [y]
+/// addr = "/MyPen/Y"
+/// range = [-1.0, 1.0]
+/// invert = true
+///
+/// [pen_down]
+/// addr = "/MyPen/Draw"
+/// type = "bool"
+///
+/// [hue]
+/// addr = "/MyPen/Hue"
+///
+/// [motion]
+/// kind = "s_curve"
+/// max_velocity = 1.0
+/// ```
+///
+/// The channels the avatar doesn't have are left out, and never sent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AvatarProfile {
+    pub name: String,
+    /// The ids of the avatars to switch to the profile on, such as `avtr_...`.
+    #[serde(default)]
+    pub avatars: Vec<String>,
+    /// How the color is sent, guessed from the color channels when left out.
+    pub color_mode: Option<ColorMode>,
+    pub x: ParameterBinding,
+    pub y: ParameterBinding,
+    /// The depth of the pen, pushed to 1.0 while drawing and pulled to 0.0 while lifted.
+    pub z: Option<ParameterBinding>,
+    /// Enables the pen on the avatar before moving it for the first time.
+    pub enabled: Option<ParameterBinding>,
+    pub pen_down: Option<ParameterBinding>,
+    /// The brush width from 0.0 to 1.0.
+    pub width: Option<ParameterBinding>,
+    /// The index in the palette, sent as is.
+    pub color: Option<ParameterBinding>,
+    pub hue: Option<ParameterBinding>,
+    pub saturation: Option<ParameterBinding>,
+    pub value: Option<ParameterBinding>,
+    pub eraser: Option<ParameterBinding>,
+    /// Clears the drawing on the avatar when switched on.
+    pub clear: Option<ParameterBinding>,
+    /// The motion limits the pen on the avatar follows, instead of the ones in the preference.
+    pub motion: Option<MotionProfile>,
+}
+
+impl Default for AvatarProfile {
+    fn default() -> Self {
+        use ParameterType::*;
+
+        Self {
+            name: "Default".to_string(),
+            avatars: Vec::new(),
+            color_mode: None,
+            x: ParameterBinding::new("/Pen_X", Float).with_range((-1.0, 1.0)),
+            y: ParameterBinding::new("/Pen_Y", Float).with_range((-1.0, 1.0)),
+            z: None,
+            enabled: Some(ParameterBinding::new("/Pen_Enabled", Bool)),
+            pen_down: Some(ParameterBinding::new("/on_drawing", Bool)),
+            width: Some(ParameterBinding::new("/Pen_Width", Float)),
+            color: Some(ParameterBinding::new("/Pen_Color", Int)),
+            hue: Some(ParameterBinding::new("/Pen_Hue", Float)),
+            saturation: Some(ParameterBinding::new("/Pen_Saturation", Float)),
+            value: Some(ParameterBinding::new("/Pen_Value", Float)),
+            eraser: Some(ParameterBinding::new("/Pen_Eraser", Bool)),
+            clear: None,
+            motion: None,
+        }
+    }
+}
+
+impl AvatarProfile {
+    pub const EXTENSIONS: [&str; 2] = ["toml", "json"];
+
+    pub fn from_toml(s: &str) -> Result<Self, String> {
+        toml::from_str(s).map_err(|e| e.to_string())
+    }
+
+    pub fn from_json(s: &str) -> Result<Self, String> {
+        serde_json::from_str(s).map_err(|e| e.to_string())
+    }
+
+    /// Load the profile from the file, parsing it by the extension.
+    pub fn load(path: &Path) -> Result<Self> {
+        let s = std::fs::read_to_string(path).map_err(|e| VrcCanvasError::InvalidProfile {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })?;
+
+        let profile = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&s),
+            Some("json") => Self::from_json(&s),
+            _ => Err(format!("The extension isn't any of {:?}", Self::EXTENSIONS)),
+        };
+
+        profile.map_err(|reason| VrcCanvasError::InvalidProfile {
+            path: path.to_path_buf(),
+            reason,
+        })
+    }
+
+    /// How the color is sent, in the palette index unless the avatar only has the HSV channels.
+    pub fn color_mode(&self) -> ColorMode {
+        match self.color_mode {
+            Some(mode) => mode,
+            None if self.color.is_none() && self.hue.is_some() => ColorMode::Hsv,
+            None => ColorMode::PaletteIndex,
+        }
+    }
+
+    /// Every channel bound to a parameter, with the name of the field.
+    pub fn channels(&self) -> Vec<(&'static str, &ParameterBinding)> {
+        [
+            ("x", Some(&self.x)),
+            ("y", Some(&self.y)),
+            ("z", self.z.as_ref()),
+            ("enabled", self.enabled.as_ref()),
+            ("pen_down", self.pen_down.as_ref()),
+            ("width", self.width.as_ref()),
+            ("color", self.color.as_ref()),
+            ("hue", self.hue.as_ref()),
+            ("saturation", self.saturation.as_ref()),
+            ("value", self.value.as_ref()),
+            ("eraser", self.eraser.as_ref()),
+            ("clear", self.clear.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(channel, binding)| Some((channel, binding?)))
+        .collect()
+    }
+
+    /// Save the profile as TOML, to be loaded from the profiles directory.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let s = toml::to_string_pretty(self).map_err(|e| VrcCanvasError::InvalidProfile {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })?;
+        std::fs::write(path, s)?;
+
+        Ok(())
+    }
+
+    /// Whether to switch to the profile on the avatar.
+    pub fn matches(&self, avatar_id: &str) -> bool {
+        self.avatars.iter().any(|id| id == avatar_id)
+    }
+}
+
+/// Load every profile in the directory, skipping the files which aren't the valid profiles.
+pub fn load_profiles(dir: &Path) -> Result<Vec<AvatarProfile>> {
+    let mut paths = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| AvatarProfile::EXTENSIONS.contains(&ext))
+        })
+        .collect::<Vec<_>>();
+    // Keep the order stable between the reloads.
+    paths.sort();
+
+    let profiles = paths
+        .iter()
+        .filter_map(|path| match AvatarProfile::load(path) {
+            Ok(profile) => Some(profile),
+            Err(e) => {
+                log::error!("Skipped the profile: {}", e);
+                None
+            }
+        })
+        .collect();
+
+    Ok(profiles)
+}
+
+/// The path to save a new profile in the directory, numbered not to overwrite any existing profile.
+pub fn new_profile_path(dir: &Path, stem: &str) -> PathBuf {
+    (1..)
+        .map(|n| match n {
+            1 => stem.to_string(),
+            n => format!("{}-{}", stem, n),
+        })
+        .find(|name| {
+            AvatarProfile::EXTENSIONS
+                .iter()
+                .all(|ext| !dir.join(format!("{}.{}", name, ext)).exists())
+        })
+        .map(|name| dir.join(format!("{}.toml", name)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_mode_follows_channels() {
+        let profile = AvatarProfile::default();
+        assert_eq!(profile.color_mode(), ColorMode::PaletteIndex);
+
+        let hsv = AvatarProfile {
+            color: None,
+            ..AvatarProfile::default()
+        };
+        assert_eq!(hsv.color_mode(), ColorMode::Hsv);
+
+        let chosen = AvatarProfile {
+            color_mode: Some(ColorMode::Hsv),
+            ..AvatarProfile::default()
+        };
+        assert_eq!(chosen.color_mode(), ColorMode::Hsv);
+    }
+
+    #[test]
+    fn color_mode_round_trips() {
+        let profile = AvatarProfile {
+            color_mode: Some(ColorMode::Hsv),
+            ..AvatarProfile::default()
+        };
+        let s = toml::to_string_pretty(&profile).unwrap();
+
+        assert!(s.contains("color_mode = \"hsv\""));
+        assert_eq!(AvatarProfile::from_toml(&s).unwrap(), profile);
+    }
+
+    #[test]
+    fn new_profile_path_keeps_existing() {
+        let dir = std::env::temp_dir().join("vrc_canvas_new_profile_path");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = new_profile_path(&dir, "avtr_pen");
+        assert_eq!(path, dir.join("avtr_pen.toml"));
+
+        std::fs::write(&path, "").unwrap();
+        std::fs::write(dir.join("avtr_pen-2.json"), "").unwrap();
+        assert_eq!(
+            new_profile_path(&dir, "avtr_pen"),
+            dir.join("avtr_pen-3.toml")
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_reports_path() {
+        let path = std::env::temp_dir().join("vrc_canvas_missing_profile.toml");
+
+        assert!(matches!(
+            AvatarProfile::load(&path),
+            Err(VrcCanvasError::InvalidProfile { path: reported, .. }) if reported == path
+        ));
+    }
+}
@@ -0,0 +1,58 @@
+use std::sync::{Arc, RwLock};
+
+use tokio::sync::broadcast;
+
+use super::{
+    pen_handle::{
+        actor, actor::PenActorHandle, feedback::MoveAck, PenHandler, PenSettings, PenState,
+    },
+    OscConfig, OscHandler, Result,
+};
+
+/// The session started by [`super::start_osc`], for the callers which don't keep their own.
+pub static SESSION: RwLock<Option<Session>> = RwLock::new(None);
+
+/// A session with VRChat, owning its sockets and its pen.
+///
+/// The sessions are independent of each other, so several of them can run at once
+/// with the different configs, e.g. for the avatars on the different VRChat instances.
+#[derive(Debug, Clone)]
+pub struct Session {
+    osc: Arc<OscHandler>,
+    pen: PenActorHandle,
+}
+
+impl Session {
+    /// Bind the sockets and spawn the actor owning the pen.
+    pub fn start(current_state: Option<PenState>, config: OscConfig) -> Result<Self> {
+        let osc = Arc::new(OscHandler::new(config)?);
+        let handler = PenHandler::new(osc.clone(), current_state.unwrap_or_default());
+        let pen = actor::spawn(handler, PenSettings::default());
+
+        Ok(Self { osc, pen })
+    }
+
+    /// The session started by [`super::start_osc`].
+    pub fn current() -> Option<Self> {
+        SESSION.read().unwrap().clone()
+    }
+
+    pub fn osc(&self) -> &Arc<OscHandler> {
+        &self.osc
+    }
+
+    pub fn pen(&self) -> &PenActorHandle {
+        &self.pen
+    }
+
+    /// Subscribe the acknowledgements of the moves of the pen of this session only.
+    pub fn subscribe_acks(&self) -> broadcast::Receiver<MoveAck> {
+        self.pen.subscribe_acks()
+    }
+
+    /// Stop the pen after lifting it and sending the reset values, then stop receiving.
+    pub async fn stop(&self) {
+        self.pen.shutdown().await;
+        self.osc.stop().await;
+    }
+}
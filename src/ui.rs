@@ -1,17 +1,82 @@
-use anyhow::Result;
 use eframe::{
     egui::{self, FontData, FontDefinitions},
     epaint::FontFamily,
 };
 use rust_i18n::t;
 
-use crate::osc::{self, pen_handle};
+use crate::osc::{
+    self, avatar_config,
+    pen_handle::{
+        self,
+        brush::{BrushConfig, Stylus},
+        color::{ColorConfig, ColorMode, PenColor},
+        command::PenCommand,
+        coordinate::{self, CoordinateMapping, Point},
+        feedback::{FeedbackConfig, MoveAck},
+        motion::{MotionProfile, MotionProfileKind},
+        profile::{self, AvatarProfile},
+        PenSettings, ResetValues, SettleDelays,
+    },
+};
+
+impl From<egui::Pos2> for Point {
+    fn from(pos: egui::Pos2) -> Self {
+        Point::new(pos.x, pos.y)
+    }
+}
+
+impl From<Point> for egui::Pos2 {
+    fn from(point: Point) -> Self {
+        egui::pos2(point.x, point.y)
+    }
+}
 
 pub struct Canvas {
     canvas_size: f32,
     active_rect: egui::Rect,
-    pen_handler: Option<pen_handle::PenHandler>,
-    osc_started: bool,
+    /// The height of the toolbar above the canvas, which the window is made taller by.
+    toolbar_height: f32,
+    /// The session owned by the canvas while it is started.
+    session: Option<osc::Session>,
+    osc_config: osc::OscConfig,
+    osc_error: Option<osc::VrcCanvasError>,
+    last_pointer_input: Option<PointerInput>,
+    /// The pointer buttons held down, tracked from the raw events.
+    pressed_buttons: u8,
+    /// The time of the last frame the pointer events have been collected in.
+    last_pointer_time: f64,
+    /// The last pointer sample to measure the velocity from.
+    last_pointer_sample: Option<PointerSample>,
+    last_target_state: Option<pen_handle::PenState>,
+    /// The commands waiting for the room in the pen command queue.
+    pending_commands: std::collections::VecDeque<PenCommand>,
+    mapping: CoordinateMapping,
+    motion: MotionProfile,
+    settle: SettleDelays,
+    reset: ResetValues,
+    /// The interval between the intermediate points sent along a stroke.
+    tick_rate: std::time::Duration,
+    feedback: FeedbackConfig,
+    brush: BrushConfig,
+    color_config: ColorConfig,
+    /// The color mode chosen on the canvas over the one of the profile, not saved in the profile.
+    color_mode_override: Option<ColorMode>,
+    /// The built-in profile followed by the ones loaded from the profiles directory.
+    profiles: Vec<AvatarProfile>,
+    profile_index: usize,
+    /// The avatar the profile has been switched for last.
+    last_avatar: Option<String>,
+    /// The result of checking the profile against the avatar config written by VRChat.
+    mapping_check: Option<osc::Result<Vec<avatar_config::MappingIssue>>>,
+    /// Where the last suggested profile has been saved.
+    suggested_path: Option<std::path::PathBuf>,
+    /// Whether erasing instead of drawing, toggled with the button or the shortcut.
+    eraser: bool,
+    /// The strokes drawn so far, previewed on the canvas in their colors.
+    strokes: Vec<PreviewStroke>,
+    /// The acknowledgements of the moves of the current session.
+    move_acks: Option<tokio::sync::broadcast::Receiver<MoveAck>>,
+    last_move_ack: Option<MoveAck>,
     preference: CanvasPreference,
 }
 
@@ -30,8 +95,34 @@ impl Default for Canvas {
                     preference.aspect_ratio.y * Self::CANVAS_SIZE_DEFAULT,
                 ),
             ),
-            pen_handler: None,
-            osc_started: false,
+            toolbar_height: 0.0,
+            session: None,
+            osc_config: osc::OscConfig::default(),
+            osc_error: None,
+            last_pointer_input: None,
+            pressed_buttons: 0,
+            last_pointer_time: 0.0,
+            last_pointer_sample: None,
+            last_target_state: None,
+            pending_commands: std::collections::VecDeque::new(),
+            mapping: CoordinateMapping::default(),
+            motion: MotionProfile::default(),
+            settle: SettleDelays::default(),
+            reset: ResetValues::default(),
+            tick_rate: pen_handle::SAMPLE_INTERVAL_DEFAULT,
+            feedback: FeedbackConfig::default(),
+            brush: BrushConfig::default(),
+            color_config: ColorConfig::default(),
+            color_mode_override: None,
+            profiles: vec![AvatarProfile::default()],
+            profile_index: 0,
+            last_avatar: None,
+            mapping_check: None,
+            suggested_path: None,
+            eraser: false,
+            strokes: Vec::new(),
+            move_acks: None,
+            last_move_ack: None,
             preference,
         }
     }
@@ -40,6 +131,23 @@ impl Default for Canvas {
 pub struct CanvasPreference {
     aspect_ratio: egui::Vec2,
     zoom_ratio: f32,
+    /// Whether to move the pen without drawing while the stylus hovers.
+    hover: bool,
+    palette: Vec<egui::Color32>,
+    /// The index of the color drawing with in the palette.
+    color_index: usize,
+    /// The directory the avatar profiles are loaded from.
+    profiles_dir: String,
+    /// The directory VRChat writes the avatar configs in.
+    osc_dir: String,
+}
+
+/// A stroke previewed on the canvas, at the positions normalized in the canvas.
+struct PreviewStroke {
+    color: egui::Color32,
+    eraser: bool,
+    points: Vec<egui::Pos2>,
+    finished: bool,
 }
 
 impl Default for CanvasPreference {
@@ -47,6 +155,13 @@ impl Default for CanvasPreference {
         Self {
             aspect_ratio: Self::ASPECT_RATIO_DEFAULT,
             zoom_ratio: Self::ZOOM_RATIO_DEFAULT,
+            hover: true,
+            palette: Self::PALETTE_DEFAULT.to_vec(),
+            color_index: 0,
+            profiles_dir: String::new(),
+            osc_dir: avatar_config::default_osc_dir()
+                .map(|dir| dir.display().to_string())
+                .unwrap_or_default(),
         }
     }
 }
@@ -54,6 +169,56 @@ impl Default for CanvasPreference {
 impl CanvasPreference {
     pub const ZOOM_RATIO_DEFAULT: f32 = 2.0;
     pub const ASPECT_RATIO_DEFAULT: egui::Vec2 = egui::vec2(16.0, 9.0);
+    pub const PALETTE_DEFAULT: [egui::Color32; 6] = [
+        egui::Color32::WHITE,
+        egui::Color32::BLACK,
+        egui::Color32::RED,
+        egui::Color32::GREEN,
+        egui::Color32::BLUE,
+        egui::Color32::YELLOW,
+    ];
+    const PALETTE_KEY: &str = "palette";
+    const PROFILES_DIR_KEY: &str = "profiles_dir";
+    const OSC_DIR_KEY: &str = "osc_dir";
+
+    fn color(&self) -> egui::Color32 {
+        self.palette
+            .get(self.color_index)
+            .copied()
+            .unwrap_or(egui::Color32::WHITE)
+    }
+
+    /// The color sent to the avatar, both as the index and as HSV.
+    fn pen_color(&self) -> PenColor {
+        let hsva = egui::ecolor::Hsva::from(self.color());
+
+        PenColor {
+            index: self.color_index as i32,
+            hsv: (hsva.h, hsva.s, hsva.v),
+        }
+    }
+}
+
+/// Serialize the palette as the comma separated hex codes, such as `ffffff,ff0000`.
+fn palette_to_string(palette: &[egui::Color32]) -> String {
+    palette
+        .iter()
+        .map(|color| format!("{:02x}{:02x}{:02x}", color.r(), color.g(), color.b()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn palette_from_string(palette: &str) -> Option<Vec<egui::Color32>> {
+    let palette = palette
+        .split(',')
+        .map(|hex| {
+            let rgb = u32::from_str_radix(hex.trim(), 16).ok()?;
+            let [_, r, g, b] = rgb.to_be_bytes();
+            Some(egui::Color32::from_rgb(r, g, b))
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    (!palette.is_empty()).then_some(palette)
 }
 
 impl Canvas {
@@ -76,17 +241,35 @@ impl Canvas {
 
         cc.egui_ctx.set_fonts(fonts);
 
-        if let Some(_storage) = cc.storage {
-            // TODO: load preference from storage
+        let mut canvas = Self::default();
+
+        if let Some(storage) = cc.storage {
+            // TODO: load the rest of preference from storage
+            if let Some(palette) = storage
+                .get_string(CanvasPreference::PALETTE_KEY)
+                .and_then(|palette| palette_from_string(&palette))
+            {
+                canvas.preference.palette = palette;
+            }
+            if let Some(profiles_dir) = storage.get_string(CanvasPreference::PROFILES_DIR_KEY) {
+                canvas.preference.profiles_dir = profiles_dir;
+                canvas.reload_profiles();
+            }
+            if let Some(osc_dir) = storage.get_string(CanvasPreference::OSC_DIR_KEY) {
+                canvas.preference.osc_dir = osc_dir;
+            }
         }
 
-        Default::default()
+        canvas
     }
 
     pub const ACTIVE_RECT_MARGIN: f32 = 50.0;
 
     pub const CANVAS_SIZE_DEFAULT: f32 = 50.0;
 
+    const STROKE_PREVIEW_WIDTH: f32 = 2.0;
+    const ERASER_PREVIEW_WIDTH: f32 = 10.0;
+
     const DEFAULT_POS: egui::Pos2 = egui::pos2(0f32, 0f32);
 
     fn init_active_rect(&self, pos: Option<egui::Pos2>) -> egui::Rect {
@@ -111,108 +294,1150 @@ impl Canvas {
 
         frame.set_window_size(egui::vec2(
             canvas_face.x + Self::ACTIVE_RECT_MARGIN * 2.0,
-            canvas_face.y + Self::ACTIVE_RECT_MARGIN * 2.0,
+            canvas_face.y + Self::ACTIVE_RECT_MARGIN * 2.0 + self.toolbar_height,
         ));
     }
 
+    /// Normalize the position so that the active rect spans from 0.0 to 1.0 on both axes.
+    fn normalize(&self, pos: egui::Pos2) -> egui::Pos2 {
+        ((pos - self.active_rect.min) / self.active_rect.size()).to_pos2()
+    }
+
+    fn denormalize(&self, pos: egui::Pos2) -> egui::Pos2 {
+        self.active_rect.min + pos.to_vec2() * self.active_rect.size()
+    }
+
+    /// Normalize the position, only if it is in the active rect.
     pub fn from_absolute_to_relative(&self, pos: egui::Pos2) -> Option<egui::Pos2> {
-        let relative = (pos - self.active_rect.min).to_pos2();
+        let relative = self.normalize(pos);
 
-        if relative.x < 0.0 || relative.y < 0.0 {
+        if !egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0)).contains(relative)
+        {
             return None;
         }
 
         Some(relative)
     }
 
-    fn update_target_state(&mut self, pos: Option<egui::Pos2>) -> Result<()> {
-        let handler = match pen_handle::PEN_HANDLER.get() {
-            Some(handler) => handler,
-            None => return Err(anyhow::anyhow!("PenHandler is not initialized")),
-        };
+    /// Convert the pointer input into the pen states, cutting the stroke at the edges of the active rect.
+    ///
+    /// The pen lifts where the stroke leaves the active rect, and a new stroke starts where it comes back in.
+    fn pen_states_from_pointer(
+        &mut self,
+        pointer_input: Option<PointerInput>,
+        stylus: Stylus,
+    ) -> Vec<pen_handle::PenState> {
+        let previous = std::mem::replace(&mut self.last_pointer_input, pointer_input);
+        let mut states = Vec::new();
+
+        match (previous, pointer_input) {
+            (Some(PointerInput::Pressing(from)), Some(PointerInput::Pressing(to))) => {
+                let (from, to) = (self.normalize(from), self.normalize(to));
 
-        tokio::spawn(async move {
-            let mut handler = handler.lock().await;
-            match pos {
-                Some(pos) => {
-                    let target_state = Some(pen_handle::PenState::drawing_from_pos(pos));
-                    *handler = handler.set_target_state(target_state);
+                if let Some(segment) = coordinate::clip_segment(from.into(), to.into()) {
+                    let (x, y) = segment.to;
+                    if segment.entered {
+                        states.push(pen_handle::PenState::Drawing(
+                            segment.from.0,
+                            segment.from.1,
+                            stylus,
+                        ));
+                    }
+                    states.push(pen_handle::PenState::Drawing(x, y, stylus));
+                    if segment.exited {
+                        states.push(pen_handle::PenState::Idle(x, y));
+                    }
                 }
-                None => {
-                    *handler = handler.set_target_state(None);
+            }
+            (_, Some(PointerInput::Pressing(pos))) => {
+                states.extend(
+                    self.from_absolute_to_relative(pos)
+                        .map(|pos| pen_handle::PenState::drawing_from_pos(pos.into(), stylus)),
+                );
+            }
+            (_, Some(PointerInput::Hovering(pos))) if self.preference.hover => {
+                states.extend(
+                    self.from_absolute_to_relative(pos)
+                        .map(|pos| pen_handle::PenState::idle_from_pos(pos.into())),
+                );
+            }
+            _ => {}
+        }
+
+        // Lift the pen where the stroke has ended.
+        if states.is_empty() {
+            if let Some(pen_handle::PenState::Drawing(x, y, _)) = self.last_target_state {
+                states.push(pen_handle::PenState::Idle(x, y));
+            }
+        }
+
+        states
+    }
+
+    /// Read the stylus input from the sample, simulating the pressure from the velocity without the force.
+    fn stylus_from_sample(&mut self, sample: PointerSample) -> Stylus {
+        let previous = self.last_pointer_sample.replace(sample);
+
+        let pressure = match (sample.force, previous, sample.input) {
+            (Some(force), ..) if force > 0.0 => force,
+            (
+                _,
+                Some(PointerSample {
+                    input: Some(PointerInput::Pressing(from)),
+                    time,
+                    ..
+                }),
+                Some(PointerInput::Pressing(to)),
+            ) if self.brush.simulate_pressure && sample.time > time => {
+                let distance = self.normalize(from).distance(self.normalize(to));
+                self.brush
+                    .simulated_pressure(distance / (sample.time - time) as f32)
+            }
+            _ => Stylus::default().pressure,
+        };
+
+        // egui doesn't report the eraser end, but the barrel button comes as the secondary button.
+        let eraser = self.eraser || sample.pressed_buttons & SECONDARY_BUTTON != 0;
+
+        // egui doesn't report the tilt yet.
+        Stylus {
+            pressure,
+            tilt: None,
+            eraser,
+        }
+    }
+
+    /// Record the pen states to preview the strokes in the color drawing with.
+    ///
+    /// A stroke is split where it switches between drawing and erasing.
+    fn record_preview(&mut self, states: &[pen_handle::PenState]) {
+        let color = self.preference.color();
+
+        for state in states {
+            match *state {
+                pen_handle::PenState::Drawing(x, y, stylus) => match self.strokes.last_mut() {
+                    Some(stroke) if !stroke.finished && stroke.eraser == stylus.eraser => {
+                        stroke.points.push(egui::pos2(x, y))
+                    }
+                    _ => self.strokes.push(PreviewStroke {
+                        color,
+                        eraser: stylus.eraser,
+                        points: vec![egui::pos2(x, y)],
+                        finished: false,
+                    }),
+                },
+                pen_handle::PenState::Idle(..) => {
+                    if let Some(stroke) = self.strokes.last_mut() {
+                        stroke.finished = true;
+                    }
                 }
+            }
+        }
+    }
+
+    /// Paint the strokes in order, so the erasing ones paint over the others with the background.
+    fn paint_preview(&self, painter: &egui::Painter, background: egui::Color32) {
+        let to_absolute = |point: &egui::Pos2| self.denormalize(*point);
+
+        for stroke in &self.strokes {
+            let (color, width) = if stroke.eraser {
+                (background, Self::ERASER_PREVIEW_WIDTH)
+            } else {
+                (stroke.color, Self::STROKE_PREVIEW_WIDTH)
             };
+
+            match stroke.points.as_slice() {
+                [point] => {
+                    painter.circle_filled(to_absolute(point), width / 2.0, color);
+                }
+                points => {
+                    painter.add(egui::Shape::line(
+                        points.iter().map(to_absolute).collect(),
+                        egui::Stroke::new(width, color),
+                    ));
+                }
+            }
+        }
+    }
+
+    fn submit_commands(&mut self, commands: impl IntoIterator<Item = PenCommand>) {
+        self.pending_commands.extend(commands);
+        self.flush_commands();
+    }
+
+    /// Move the pending commands into the queue as long as it has room, keeping the rest for the next frame.
+    fn flush_commands(&mut self) {
+        let commands = match &self.session {
+            Some(session) => session.pen().commands(),
+            None => return,
+        };
+
+        while let Some(command) = self.pending_commands.pop_front() {
+            if let Err(command) = commands.try_send(command) {
+                self.pending_commands.push_front(command);
+                break;
+            }
+        }
+    }
+
+    /// The number of the commands not executed yet, including the pending ones.
+    fn queue_depth(&self) -> usize {
+        let queued = self
+            .session
+            .as_ref()
+            .map_or(0, |session| session.pen().commands().depth());
+        queued + self.pending_commands.len()
+    }
+
+    /// Send the settings to the pen, which picks them up when the session starts otherwise.
+    fn update_pen_settings(&self) {
+        let session = match &self.session {
+            Some(session) => session,
+            None => return,
+        };
+
+        session.pen().update_settings(PenSettings {
+            mapping: CoordinateMapping {
+                aspect_ratio: self.preference.aspect_ratio.into(),
+                ..self.mapping
+            },
+            motion: self.motion,
+            settle: self.settle,
+            feedback: self.feedback,
+            brush: self.brush,
+            color_config: self.color_config,
+            reset: self.reset,
+            tick_rate: self.tick_rate,
+            profile: AvatarProfile {
+                color_mode: self.color_mode_override.or(self.profile().color_mode),
+                ..self.profile().clone()
+            },
         });
-        Ok(())
     }
-}
 
-fn get_interact_pos(input_state: &egui::InputState) -> Option<egui::Pos2> {
-    let pointer = &input_state.pointer;
-    let is_down = pointer.any_down();
-    let is_moving = pointer.is_moving();
+    fn start_osc(&mut self) {
+        match osc::Session::start(None, self.osc_config.clone()) {
+            Ok(session) => {
+                self.move_acks = Some(session.subscribe_acks());
+                self.session = Some(session);
+                self.osc_error = None;
+                self.update_pen_settings();
+                let color = self.preference.pen_color();
+                self.submit_commands([PenCommand::SetColor(color)]);
+            }
+            Err(e) => {
+                log::error!("Failed to start osc: {}", e);
+                self.osc_error = Some(e);
+            }
+        }
+    }
+
+    /// Stop the session, waiting for the pen to be lifted and reset.
+    fn stop_osc(&mut self) {
+        if let Some(session) = self.session.take() {
+            block_on(session.stop());
+        }
 
-    if !is_down {
-        return None;
+        self.pending_commands.clear();
+        // The pen has been lifted, so the next stroke starts from travelling.
+        self.last_target_state = None;
     }
 
-    pointer.interact_pos()
+    fn receive_move_acks(&mut self) {
+        let move_acks = match &mut self.move_acks {
+            Some(move_acks) => move_acks,
+            None => return,
+        };
+
+        loop {
+            match move_acks.try_recv() {
+                Ok(ack) => self.last_move_ack = Some(ack),
+                Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn profile(&self) -> &AvatarProfile {
+        &self.profiles[self.profile_index]
+    }
+
+    /// Load the profiles from the profiles directory again, keeping the built-in one.
+    fn reload_profiles(&mut self) {
+        let name = self.profile().name.clone();
+        self.profiles.truncate(1);
+
+        if !self.preference.profiles_dir.is_empty() {
+            match profile::load_profiles(self.preference.profiles_dir.as_ref()) {
+                Ok(profiles) => {
+                    log::info!("Loaded {} profiles", profiles.len());
+                    self.profiles.extend(profiles);
+                }
+                Err(e) => log::error!("Failed to load the profiles: {}", e),
+            }
+        }
+
+        // Keep the profile selected if it is still there.
+        self.profile_index = self
+            .profiles
+            .iter()
+            .position(|profile| profile.name == name)
+            .unwrap_or(0);
+        self.update_pen_settings();
+    }
+
+    /// Switch to the profile for the avatar VRChat has reported, if any profile is for it.
+    fn follow_avatar(&mut self) {
+        let avatar = match &self.session {
+            Some(session) => session.osc().current_avatar(),
+            None => return,
+        };
+        if avatar == self.last_avatar {
+            return;
+        }
+
+        if let Some(index) = avatar.as_deref().and_then(|avatar| {
+            self.profiles
+                .iter()
+                .position(|profile| profile.matches(avatar))
+        }) {
+            log::info!("Switched to the profile {}", self.profiles[index].name);
+            self.profile_index = index;
+            self.update_pen_settings();
+        }
+        self.last_avatar = avatar;
+
+        if !self.preference.osc_dir.is_empty() {
+            self.check_mapping();
+        }
+    }
+
+    /// The config of the current avatar, or the one written last when it isn't known yet.
+    fn load_avatar_config(&self) -> osc::Result<avatar_config::AvatarConfig> {
+        avatar_config::load_config(
+            self.preference.osc_dir.as_ref(),
+            self.last_avatar.as_deref(),
+        )
+    }
+
+    /// Check the parameters of the profile exist on the avatar with the right types.
+    fn check_mapping(&mut self) {
+        let check = self
+            .load_avatar_config()
+            .map(|config| config.validate(self.profile(), &self.osc_config));
+
+        match &check {
+            Ok(issues) => {
+                for issue in issues {
+                    log::warn!("{}", issue);
+                }
+            }
+            Err(e) => log::error!("Failed to check the profile: {}", e),
+        }
+        self.mapping_check = Some(check);
+    }
+
+    /// Add the profile guessed from the avatar config, saving it in the profiles directory.
+    fn suggest_profile(&mut self) {
+        let config = match self.load_avatar_config() {
+            Ok(config) => config,
+            Err(e) => {
+                log::error!("Failed to suggest the profile: {}", e);
+                self.mapping_check = Some(Err(e));
+                return;
+            }
+        };
+        let profile = match config.suggest_profile(&self.osc_config) {
+            Some(profile) => profile,
+            None => {
+                log::warn!(
+                    "Couldn't find the pen position parameters on {}",
+                    config.name
+                );
+                return;
+            }
+        };
+
+        self.suggested_path = None;
+        if !self.preference.profiles_dir.is_empty() {
+            // Keep the profile already saved for the avatar, which may have been edited.
+            let path = profile::new_profile_path(
+                std::path::Path::new(&self.preference.profiles_dir),
+                &config.id,
+            );
+            match profile.save(&path) {
+                Ok(()) => {
+                    log::info!("Saved the profile to {}", path.display());
+                    self.suggested_path = Some(path);
+                }
+                Err(e) => log::error!("Failed to save the profile: {}", e),
+            }
+        }
+
+        self.profiles.push(profile);
+        self.profile_index = self.profiles.len() - 1;
+        self.update_pen_settings();
+        self.check_mapping();
+    }
 }
 
-impl eframe::App for Canvas {
-    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
-        egui::CentralPanel::default().show(ctx, |ui| {
-            ui.menu_button(t!("Preference.Preference"), |ui| {
+impl Canvas {
+    fn osc_preference_ui(&mut self, ui: &mut egui::Ui) {
+        ui.label(t!("Preference.Osc"));
+        ui.horizontal(|ui| {
+            ui.label(format!("{}: ", t!("Preference.Host")));
+            ui.text_edit_singleline(&mut self.osc_config.host);
+        });
+        ui.horizontal(|ui| {
+            ui.label(format!("{}: ", t!("Preference.InPort")));
+            ui.add(egui::DragValue::new(&mut self.osc_config.in_port).clamp_range(1..=u16::MAX));
+            ui.label(format!("{}: ", t!("Preference.OutPort")));
+            ui.add(egui::DragValue::new(&mut self.osc_config.out_port).clamp_range(1..=u16::MAX));
+        });
+        ui.horizontal(|ui| {
+            ui.label(format!("{}: ", t!("Preference.BaseAddress")));
+            ui.text_edit_singleline(&mut self.osc_config.base_addr);
+        });
+        ui.checkbox(&mut self.osc_config.inbound, t!("Preference.Inbound"));
+
+        if self.session.is_some() && ui.button(t!("Preference.Apply")).clicked() {
+            self.apply_osc_config();
+        }
+    }
+
+    fn apply_osc_config(&mut self) {
+        let result = match &self.session {
+            Some(session) => block_on(session.osc().rebind(self.osc_config.clone())),
+            None => return,
+        };
+
+        match result {
+            Ok(_) => self.osc_error = None,
+            Err(e) => {
+                log::error!("Failed to apply the OSC config: {}", e);
+                self.osc_error = Some(e);
+            }
+        }
+    }
+
+    fn profile_preference_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = false;
+
+        ui.horizontal(|ui| {
+            ui.label(format!("{}: ", t!("Preference.Profile")));
+            egui::ComboBox::from_id_source("avatar_profile")
+                .selected_text(self.profile().name.clone())
+                .show_ui(ui, |ui| {
+                    for (index, profile) in self.profiles.iter().enumerate() {
+                        changed |= ui
+                            .selectable_value(&mut self.profile_index, index, &profile.name)
+                            .changed();
+                    }
+                });
+        });
+        ui.horizontal(|ui| {
+            ui.label(format!("{}: ", t!("Preference.ProfilesDirectory")));
+            ui.text_edit_singleline(&mut self.preference.profiles_dir);
+            if ui.button(t!("Preference.Reload")).clicked() {
+                self.reload_profiles();
+            }
+        });
+        if let Some(avatar) = &self.last_avatar {
+            ui.label(format!("{}: {}", t!("Preference.CurrentAvatar"), avatar));
+        }
+        ui.horizontal(|ui| {
+            ui.label(format!("{}: ", t!("Preference.AvatarConfigDirectory")));
+            ui.text_edit_singleline(&mut self.preference.osc_dir);
+        });
+        ui.horizontal(|ui| {
+            if ui.button(t!("Preference.CheckProfile")).clicked() {
+                self.check_mapping();
+            }
+            if ui.button(t!("Preference.SuggestProfile")).clicked() {
+                self.suggest_profile();
+            }
+        });
+        if let Some(path) = &self.suggested_path {
+            ui.label(format!(
+                "{}: {}",
+                t!("Preference.SavedProfile"),
+                path.display()
+            ));
+        }
+        match &self.mapping_check {
+            Some(Ok(issues)) if issues.is_empty() => {
+                ui.colored_label(egui::Color32::GREEN, t!("Preference.ProfileMatches"));
+            }
+            Some(Ok(issues)) => {
+                for issue in issues {
+                    ui.colored_label(egui::Color32::YELLOW, issue.to_string());
+                }
+            }
+            Some(Err(e)) => {
+                ui.colored_label(egui::Color32::RED, e.to_string());
+            }
+            None => {}
+        }
+
+        if changed {
+            self.mapping_check = None;
+            if self.session.is_some() {
+                self.update_pen_settings();
+            }
+        }
+    }
+
+    fn mapping_preference_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = false;
+
+        ui.horizontal(|ui| {
+            changed |= ui
+                .checkbox(&mut self.mapping.invert_x, t!("Preference.InvertX"))
+                .changed();
+            changed |= ui
+                .checkbox(&mut self.mapping.invert_y, t!("Preference.InvertY"))
+                .changed();
+        });
+        changed |= ui
+            .checkbox(
+                &mut self.mapping.preserve_aspect_ratio,
+                t!("Preference.PreserveAspectRatio"),
+            )
+            .changed();
+
+        if changed && self.session.is_some() {
+            self.update_pen_settings();
+        }
+    }
+
+    fn motion_preference_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = false;
+
+        if self.profile().motion.is_some() {
+            ui.label(t!("Preference.ProfileMotion"));
+        }
+
+        ui.horizontal(|ui| {
+            ui.label(format!("{}: ", t!("Preference.MotionProfile")));
+            egui::ComboBox::from_id_source("motion_profile")
+                .selected_text(motion_profile_kind_label(self.motion.kind))
+                .show_ui(ui, |ui| {
+                    for kind in MotionProfileKind::ALL {
+                        changed |= ui
+                            .selectable_value(
+                                &mut self.motion.kind,
+                                kind,
+                                motion_profile_kind_label(kind),
+                            )
+                            .changed();
+                    }
+                });
+        });
+        ui.horizontal(|ui| {
+            ui.label(format!("{}: ", t!("Preference.MaxVelocity")));
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut self.motion.max_velocity)
+                        .speed(0.01)
+                        .clamp_range(0.0..=f32::MAX),
+                )
+                .changed();
+        });
+        ui.add_enabled_ui(
+            self.motion.kind != MotionProfileKind::ConstantVelocity,
+            |ui| {
                 ui.horizontal(|ui| {
-                    ui.label(format!("{}: ", t!("Preference.AspectRatio")));
-                    let mut width = self.preference.aspect_ratio.x.to_string();
-                    let mut height = self.preference.aspect_ratio.y.to_string();
-                    ui.text_edit_singleline(&mut width);
-                    ui.text_edit_singleline(&mut height);
-                    self.preference.aspect_ratio = (
-                        width.parse().unwrap_or(self.preference.aspect_ratio.x),
-                        height.parse().unwrap_or(self.preference.aspect_ratio.y),
+                    ui.label(format!("{}: ", t!("Preference.MaxAcceleration")));
+                    changed |= ui
+                        .add(
+                            egui::DragValue::new(&mut self.motion.max_acceleration)
+                                .speed(0.01)
+                                .clamp_range(0.0..=f32::MAX),
+                        )
+                        .changed();
+                });
+            },
+        );
+        ui.add_enabled_ui(self.motion.kind == MotionProfileKind::SCurve, |ui| {
+            ui.horizontal(|ui| {
+                ui.label(format!("{}: ", t!("Preference.MaxJerk")));
+                changed |= ui
+                    .add(
+                        egui::DragValue::new(&mut self.motion.max_jerk)
+                            .speed(0.01)
+                            .clamp_range(0.0..=f32::MAX),
                     )
-                        .into();
+                    .changed();
+            });
+        });
+
+        if changed && self.session.is_some() {
+            self.update_pen_settings();
+        }
+    }
+
+    fn settle_preference_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = false;
+
+        ui.horizontal(|ui| {
+            ui.label(format!("{}: ", t!("Preference.PenDownSettle")));
+            changed |= duration_drag_value(ui, &mut self.settle.pen_down);
+        });
+        ui.horizontal(|ui| {
+            ui.label(format!("{}: ", t!("Preference.PenUpSettle")));
+            changed |= duration_drag_value(ui, &mut self.settle.pen_up);
+        });
+        ui.horizontal(|ui| {
+            ui.label(format!("{}: ", t!("Preference.TickRate")));
+            changed |= duration_drag_value(ui, &mut self.tick_rate);
+        });
+
+        if changed && self.session.is_some() {
+            self.update_pen_settings();
+        }
+    }
+
+    fn brush_preference_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = ui
+            .checkbox(&mut self.brush.enabled, t!("Preference.Brush"))
+            .changed();
+
+        ui.add_enabled_ui(self.brush.enabled, |ui| {
+            for (label, value) in [
+                (t!("Preference.MinWidth"), &mut self.brush.min_width),
+                (t!("Preference.MaxWidth"), &mut self.brush.max_width),
+                (
+                    t!("Preference.TiltInfluence"),
+                    &mut self.brush.tilt_influence,
+                ),
+            ] {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{}: ", label));
+                    changed |= ui
+                        .add(
+                            egui::DragValue::new(value)
+                                .speed(0.01)
+                                .clamp_range(0.0..=1.0),
+                        )
+                        .changed();
                 });
-                ui.label(format!(
-                    "{}: {}",
-                    t!("Preference.CanvasSize"),
-                    self.canvas_size
-                ));
+            }
+            ui.horizontal(|ui| {
+                ui.label(format!("{}: ", t!("Preference.PressureGamma")));
+                changed |= ui
+                    .add(
+                        egui::DragValue::new(&mut self.brush.gamma)
+                            .speed(0.01)
+                            .clamp_range(0.1..=10.0),
+                    )
+                    .changed();
+            });
+
+            changed |= ui
+                .checkbox(
+                    &mut self.brush.simulate_pressure,
+                    t!("Preference.SimulatePressure"),
+                )
+                .changed();
+            ui.add_enabled_ui(self.brush.simulate_pressure, |ui| {
                 ui.horizontal(|ui| {
-                    ui.label(format!("{}: ", t!("Preference.ZoomRatio")));
-                    ui.add(egui::Slider::new(
-                        &mut self.preference.zoom_ratio,
-                        0.1..=5.0,
-                    ));
-                    if ui.button("+").clicked() {
-                        self.canvas_size *= self.preference.zoom_ratio;
-                        self.active_rect = self.init_active_rect(None);
-                        self.update_window_size(frame);
-                    }
-                    if ui.button("-").clicked() {
-                        self.canvas_size /= self.preference.zoom_ratio;
-                        self.active_rect = self.init_active_rect(None);
-                        self.update_window_size(frame);
-                    }
+                    ui.label(format!("{}: ", t!("Preference.SimulatedVelocity")));
+                    changed |= ui
+                        .add(
+                            egui::DragValue::new(&mut self.brush.simulated_velocity)
+                                .speed(0.01)
+                                .clamp_range(0.01..=f32::MAX),
+                        )
+                        .changed();
                 });
             });
+        });
+
+        if changed && self.session.is_some() {
+            self.update_pen_settings();
+        }
+    }
+
+    fn palette_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = false;
+
+        ui.horizontal(|ui| {
+            for (index, color) in self.preference.palette.iter().enumerate() {
+                let selected = index == self.preference.color_index;
+                let swatch = egui::Button::new("")
+                    .fill(*color)
+                    .stroke(if selected {
+                        egui::Stroke::new(2.0, egui::Color32::WHITE)
+                    } else {
+                        egui::Stroke::new(1.0, egui::Color32::GRAY)
+                    })
+                    .min_size(egui::vec2(16.0, 16.0));
+                if ui.add(swatch).clicked() && !selected {
+                    self.preference.color_index = index;
+                    changed = true;
+                }
+            }
+
+            if let Some(color) = self.preference.palette.get_mut(self.preference.color_index) {
+                changed |= egui::color_picker::color_edit_button_srgba(
+                    ui,
+                    color,
+                    egui::color_picker::Alpha::Opaque,
+                )
+                .changed();
+            }
+
+            if ui.button("+").clicked() {
+                let color = self.preference.color();
+                self.preference.palette.push(color);
+                self.preference.color_index = self.preference.palette.len() - 1;
+                changed = true;
+            }
+            if ui
+                .add_enabled(self.preference.palette.len() > 1, egui::Button::new("-"))
+                .clicked()
+            {
+                self.preference.palette.remove(self.preference.color_index);
+                self.preference.color_index = self
+                    .preference
+                    .color_index
+                    .min(self.preference.palette.len() - 1);
+                changed = true;
+            }
+        });
+
+        if changed && self.session.is_some() {
+            let color = self.preference.pen_color();
+            self.submit_commands([PenCommand::SetColor(color)]);
+        }
+    }
+
+    fn color_preference_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = ui
+            .checkbox(&mut self.color_config.enabled, t!("Preference.Color"))
+            .changed();
+
+        ui.add_enabled_ui(self.color_config.enabled, |ui| {
+            ui.horizontal(|ui| {
+                ui.label(format!("{}: ", t!("Preference.ColorMode")));
+                // The profile sets the mode by the parameters of the avatar, unless overridden here.
+                let profile_mode = self.profile().color_mode();
+                let follow_profile = format!(
+                    "{} ({})",
+                    t!("Preference.FollowProfile"),
+                    color_mode_label(profile_mode)
+                );
+                egui::ComboBox::from_id_source("color_mode")
+                    .selected_text(match self.color_mode_override {
+                        Some(mode) => color_mode_label(mode),
+                        None => follow_profile.clone(),
+                    })
+                    .show_ui(ui, |ui| {
+                        changed |= ui
+                            .selectable_value(&mut self.color_mode_override, None, follow_profile)
+                            .changed();
+                        for mode in ColorMode::ALL {
+                            changed |= ui
+                                .selectable_value(
+                                    &mut self.color_mode_override,
+                                    Some(mode),
+                                    color_mode_label(mode),
+                                )
+                                .changed();
+                        }
+                    });
+            });
+            if self.color_mode_override.is_some() {
+                ui.label(t!("Preference.ColorModeOverride"));
+            }
+        });
+
+        if changed && self.session.is_some() {
+            self.update_pen_settings();
+        }
+    }
+
+    fn reset_preference_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = ui
+            .checkbox(&mut self.reset.enabled, t!("Preference.Reset"))
+            .changed();
+
+        ui.add_enabled_ui(self.reset.enabled, |ui| {
+            ui.horizontal(|ui| {
+                ui.label(format!("{}: ", t!("Preference.ResetPosition")));
+                for value in [&mut self.reset.position.0, &mut self.reset.position.1] {
+                    changed |= ui
+                        .add(
+                            egui::DragValue::new(value)
+                                .speed(0.01)
+                                .clamp_range(-1.0..=1.0),
+                        )
+                        .changed();
+                }
+            });
+            changed |= ui
+                .checkbox(&mut self.reset.disable_pen, t!("Preference.DisablePen"))
+                .changed();
+        });
+
+        if changed && self.session.is_some() {
+            self.update_pen_settings();
+        }
+    }
+
+    fn feedback_preference_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = ui
+            .checkbox(&mut self.feedback.enabled, t!("Preference.Feedback"))
+            .changed();
+
+        ui.add_enabled_ui(self.feedback.enabled, |ui| {
+            ui.horizontal(|ui| {
+                ui.label(format!("{}: ", t!("Preference.Tolerance")));
+                changed |= ui
+                    .add(
+                        egui::DragValue::new(&mut self.feedback.tolerance)
+                            .speed(0.001)
+                            .clamp_range(0.0..=1.0),
+                    )
+                    .changed();
+            });
+            ui.horizontal(|ui| {
+                ui.label(format!("{}: ", t!("Preference.Timeout")));
+                changed |= duration_drag_value(ui, &mut self.feedback.timeout);
+            });
+            ui.horizontal(|ui| {
+                ui.label(format!("{}: ", t!("Preference.MaxRetries")));
+                changed |= ui
+                    .add(egui::DragValue::new(&mut self.feedback.max_retries).clamp_range(0..=10))
+                    .changed();
+            });
+        });
+
+        if changed {
+            // The reported position is only available when receiving from VRChat.
+            let enable_inbound = self.feedback.enabled && !self.osc_config.inbound;
+            if enable_inbound {
+                self.osc_config.inbound = true;
+            }
+
+            if self.session.is_some() {
+                if enable_inbound {
+                    self.apply_osc_config();
+                }
+                self.update_pen_settings();
+            }
+        }
+    }
+}
+
+/// Wait for the future on the UI thread, which runs inside the tokio runtime.
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(future))
+}
+
+/// Edit the duration in milliseconds, returning whether it has changed.
+fn duration_drag_value(ui: &mut egui::Ui, duration: &mut std::time::Duration) -> bool {
+    let mut millis = duration.as_millis() as u64;
+    let changed = ui
+        .add(egui::DragValue::new(&mut millis).suffix(" ms"))
+        .changed();
+    if changed {
+        *duration = std::time::Duration::from_millis(millis);
+    }
+    changed
+}
+
+fn motion_profile_kind_label(kind: MotionProfileKind) -> String {
+    match kind {
+        MotionProfileKind::ConstantVelocity => t!("MotionProfile.ConstantVelocity"),
+        MotionProfileKind::Trapezoidal => t!("MotionProfile.Trapezoidal"),
+        MotionProfileKind::SCurve => t!("MotionProfile.SCurve"),
+    }
+}
+
+fn color_mode_label(mode: ColorMode) -> String {
+    match mode {
+        ColorMode::PaletteIndex => t!("ColorMode.PaletteIndex"),
+        ColorMode::Hsv => t!("ColorMode.Hsv"),
+    }
+}
+
+/// The pointer interacting with the canvas.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PointerInput {
+    Hovering(egui::Pos2),
+    Pressing(egui::Pos2),
+}
+
+/// The bit of the secondary button in the pressed buttons, which the stylus barrel button comes as.
+const SECONDARY_BUTTON: u8 = 1 << egui::PointerButton::Secondary as u8;
+
+/// A pointer input captured from the raw events.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PointerSample {
+    input: Option<PointerInput>,
+    /// The pointer buttons held down at the input.
+    pressed_buttons: u8,
+    /// The pressure reported by the touch, if the device supports it.
+    force: Option<f32>,
+    /// When the input has arrived, in the same clock as [`egui::InputState::time`].
+    time: f64,
+}
+
+/// Collect every pointer input since the last frame from the raw events.
+///
+/// egui also reports the touches as the pointer events, so these cover the stylus and the touch screen.
+/// The touch comes before its pointer event, so the force is carried over to the pointer events following it.
+/// The events don't carry their timestamps, so they are spread evenly since the last frame.
+fn get_pointer_samples(
+    input_state: &egui::InputState,
+    pressed_buttons: &mut u8,
+    last_time: &mut f64,
+) -> Vec<PointerSample> {
+    let mut inputs = Vec::new();
+    let mut force = None;
 
-            ui.menu_button(t!("Logs"), |ui| {
-                egui_logger::logger_ui(ui);
+    for event in &input_state.events {
+        match *event {
+            egui::Event::PointerMoved(pos) => {
+                inputs.push((
+                    Some(pointer_input(pos, *pressed_buttons)),
+                    *pressed_buttons,
+                    force,
+                ));
+            }
+            egui::Event::PointerButton {
+                pos,
+                button,
+                pressed,
+                ..
+            } => {
+                let mask = 1 << button as u8;
+                if pressed {
+                    *pressed_buttons |= mask;
+                } else {
+                    *pressed_buttons &= !mask;
+                }
+                inputs.push((
+                    Some(pointer_input(pos, *pressed_buttons)),
+                    *pressed_buttons,
+                    force,
+                ));
+            }
+            egui::Event::PointerGone => inputs.push((None, *pressed_buttons, None)),
+            egui::Event::Touch {
+                force: touch_force, ..
+            } => force = Some(touch_force),
+            _ => {}
+        }
+    }
+
+    let since = std::mem::replace(last_time, input_state.time);
+    let elapsed = (input_state.time - since).max(0.0);
+    let count = inputs.len() as f64;
+
+    inputs
+        .into_iter()
+        .enumerate()
+        .map(|(i, (input, pressed_buttons, force))| PointerSample {
+            input,
+            pressed_buttons,
+            force,
+            time: since + elapsed * (i + 1) as f64 / count,
+        })
+        .collect()
+}
+
+fn pointer_input(pos: egui::Pos2, pressed_buttons: u8) -> PointerInput {
+    if pressed_buttons != 0 {
+        PointerInput::Pressing(pos)
+    } else {
+        PointerInput::Hovering(pos)
+    }
+}
+
+fn get_interact_pos(input_state: &egui::InputState) -> Option<PointerInput> {
+    let pointer = &input_state.pointer;
+
+    if pointer.any_down() {
+        pointer.interact_pos().map(PointerInput::Pressing)
+    } else {
+        pointer.hover_pos().map(PointerInput::Hovering)
+    }
+}
+
+impl Canvas {
+    fn toolbar_ui(&mut self, ctx: &egui::Context, ui: &mut egui::Ui, frame: &mut eframe::Frame) {
+        ui.menu_button(t!("Preference.Preference"), |ui| {
+            ui.horizontal(|ui| {
+                ui.label(format!("{}: ", t!("Preference.AspectRatio")));
+                let mut width = self.preference.aspect_ratio.x.to_string();
+                let mut height = self.preference.aspect_ratio.y.to_string();
+                ui.text_edit_singleline(&mut width);
+                ui.text_edit_singleline(&mut height);
+                let aspect_ratio = (
+                    width.parse().unwrap_or(self.preference.aspect_ratio.x),
+                    height.parse().unwrap_or(self.preference.aspect_ratio.y),
+                )
+                    .into();
+                if aspect_ratio != self.preference.aspect_ratio {
+                    self.preference.aspect_ratio = aspect_ratio;
+                    if self.session.is_some() {
+                        self.update_pen_settings();
+                    }
+                }
+            });
+            ui.label(format!(
+                "{}: {}",
+                t!("Preference.CanvasSize"),
+                self.canvas_size
+            ));
+            ui.horizontal(|ui| {
+                ui.label(format!("{}: ", t!("Preference.ZoomRatio")));
+                ui.add(egui::Slider::new(
+                    &mut self.preference.zoom_ratio,
+                    0.1..=5.0,
+                ));
+                if ui.button("+").clicked() {
+                    self.canvas_size *= self.preference.zoom_ratio;
+                    self.update_window_size(frame);
+                }
+                if ui.button("-").clicked() {
+                    self.canvas_size /= self.preference.zoom_ratio;
+                    self.update_window_size(frame);
+                }
             });
 
+            ui.checkbox(&mut self.preference.hover, t!("Preference.Hover"));
+
+            ui.separator();
+            self.osc_preference_ui(ui);
+
+            ui.separator();
+            self.profile_preference_ui(ui);
+            self.mapping_preference_ui(ui);
+
+            ui.separator();
+            self.motion_preference_ui(ui);
+            self.settle_preference_ui(ui);
+
+            ui.separator();
+            self.brush_preference_ui(ui);
+            self.color_preference_ui(ui);
+
+            ui.separator();
+            self.feedback_preference_ui(ui);
+
+            ui.separator();
+            self.reset_preference_ui(ui);
+        });
+
+        ui.menu_button(t!("Logs"), |ui| {
+            egui_logger::logger_ui(ui);
+        });
+
+        if self.session.is_none() {
             if ui.button(t!("Start")).clicked() {
-                if !self.osc_started {
-                    self.osc_started = true;
+                self.start_osc();
+            }
+        } else {
+            if ui.button(t!("Stop")).clicked() {
+                self.stop_osc();
+            }
+            if ui.button(t!("Restart")).clicked() {
+                self.stop_osc();
+                self.start_osc();
+            }
+        }
+
+        if ui.button(t!("Clear")).clicked() {
+            self.strokes.clear();
+            if self.session.is_some() {
+                self.submit_commands([PenCommand::Clear]);
+            }
+        }
+
+        self.palette_ui(ui);
+
+        if !ctx.wants_keyboard_input() && ctx.input(|i| i.key_pressed(egui::Key::E)) {
+            self.eraser = !self.eraser;
+        }
+        ui.toggle_value(&mut self.eraser, format!("{} (E)", t!("Eraser")));
+
+        if self.session.is_some() {
+            self.flush_commands();
+            if self.queue_depth() > 0 {
+                // Keep flushing and following the pen while the user doesn't move.
+                ctx.request_repaint();
+            }
+            ui.label(format!("{}: {}", t!("QueueDepth"), self.queue_depth()));
+        }
+
+        self.receive_move_acks();
+        self.follow_avatar();
+        if let Some(ack) = &self.last_move_ack {
+            let text = format!(
+                "{}: {} ms ({} {})",
+                t!("Latency"),
+                ack.latency.as_millis(),
+                ack.attempts,
+                t!("Attempts")
+            );
+            if ack.reached {
+                ui.label(text);
+            } else {
+                ui.colored_label(egui::Color32::YELLOW, text);
+            }
+        }
+
+        if let Some(osc_error) = &self.osc_error {
+            ui.colored_label(
+                egui::Color32::RED,
+                format!("{}: {}", t!("OscError"), osc_error),
+            );
 
-                    if let Err(e) = osc::start_osc(None) {
-                        log::error!("Failed to start osc: {}", e);
-                        self.osc_started = false;
+            // Offer the next port, which VRChat has to be told with `--osc` as well.
+            if let osc::VrcCanvasError::PortInUse { port } = *osc_error {
+                let next = port.saturating_add(1);
+                if ui
+                    .button(format!("{}: {}", t!("UseAnotherOutPort"), next))
+                    .clicked()
+                {
+                    self.osc_config.out_port = next;
+                    if self.session.is_some() {
+                        self.apply_osc_config();
+                    } else {
+                        self.start_osc();
                     }
                 }
             }
+        }
+    }
+}
+
+impl eframe::App for Canvas {
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        // Keep the controls out of the canvas, not to draw while using them.
+        let toolbar = egui::TopBottomPanel::top("toolbar").show(ctx, |ui| {
+            ui.horizontal_wrapped(|ui| self.toolbar_ui(ctx, ui, frame));
+        });
+        let toolbar_height = toolbar.response.rect.height();
+        if toolbar_height != self.toolbar_height {
+            self.toolbar_height = toolbar_height;
+            self.update_window_size(frame);
+        }
+        self.active_rect = self.init_active_rect(Some(egui::pos2(0.0, self.toolbar_height)));
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            // Only follow the pointer on the canvas, not over the popups or the other widgets.
+            let canvas = ui.interact(
+                self.active_rect,
+                ui.id().with("canvas"),
+                egui::Sense::click_and_drag(),
+            );
+            let on_canvas = canvas.dragged()
+                || canvas.drag_released()
+                || (canvas.hovered() && !ctx.memory(|memory| memory.is_anything_being_dragged()));
 
             ui.scope(|ui| {
                 let painter = ui.painter();
@@ -221,24 +1446,94 @@ impl eframe::App for Canvas {
                     egui::Rounding::default(),
                     egui::Stroke::new(1.0, egui::Color32::WHITE),
                 );
+                self.paint_preview(painter, ui.visuals().panel_fill);
 
-                if let Some(interact_pos) = ctx.input(get_interact_pos) {
+                // Show where the pen on the avatar is.
+                if let Some(session) = &self.session {
+                    let (x, y) = session.pen().subscribe_state().borrow().position();
                     painter.circle_stroke(
-                        interact_pos,
-                        5.0,
-                        egui::Stroke::new(1.0, egui::Color32::WHITE),
+                        self.denormalize(egui::pos2(x, y)),
+                        3.0,
+                        egui::Stroke::new(1.0, egui::Color32::LIGHT_BLUE),
                     );
+                }
+
+                let pointer_input = ctx.input(get_interact_pos).filter(|_| on_canvas);
+
+                match pointer_input {
+                    Some(PointerInput::Pressing(interact_pos)) => {
+                        painter.circle_stroke(
+                            interact_pos,
+                            5.0,
+                            egui::Stroke::new(1.0, egui::Color32::WHITE),
+                        );
+                    }
+                    Some(PointerInput::Hovering(hover_pos)) if self.preference.hover => {
+                        painter.circle_stroke(
+                            hover_pos,
+                            5.0,
+                            egui::Stroke::new(1.0, egui::Color32::GRAY),
+                        );
+                    }
+                    _ => {}
+                }
+
+                // Feed every pointer event since the last frame, not to miss the fast strokes.
+                let samples = ctx.input(|input_state| {
+                    get_pointer_samples(
+                        input_state,
+                        &mut self.pressed_buttons,
+                        &mut self.last_pointer_time,
+                    )
+                });
+                let mut target_states = Vec::new();
+                for mut sample in samples {
+                    // Still collected off the canvas, to keep track of the buttons held down.
+                    if !on_canvas {
+                        sample.input = None;
+                    }
+                    log::trace!("Pointer at {:.3}s: {:?}", sample.time, sample.input);
+                    let stylus = self.stylus_from_sample(sample);
+                    target_states.extend(self.pen_states_from_pointer(sample.input, stylus));
+                }
+                target_states.dedup();
+                target_states.retain(|state| Some(*state) != self.last_target_state);
+
+                if !target_states.is_empty() {
+                    log::trace!("Target states in active rect: {:?}", target_states);
+                    self.record_preview(&target_states);
 
-                    let relative_pos = self.from_absolute_to_relative(interact_pos);
-                    if let Some(relative_pos) = relative_pos {
-                        log::info!("Position in active rect: {:?}", relative_pos);
-                        self.update_target_state(Some(relative_pos))
-                            .unwrap_or_default();
+                    let mut commands = Vec::new();
+                    for state in target_states {
+                        commands.extend(PenCommand::between(self.last_target_state, state));
+                        self.last_target_state = Some(state);
+                    }
+
+                    if self.session.is_some() {
+                        self.submit_commands(commands);
                     }
-                } else if self.osc_started {
-                    self.update_target_state(None).unwrap_or_default();
                 }
             });
         });
     }
+
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        storage.set_string(
+            CanvasPreference::PALETTE_KEY,
+            palette_to_string(&self.preference.palette),
+        );
+        storage.set_string(
+            CanvasPreference::PROFILES_DIR_KEY,
+            self.preference.profiles_dir.clone(),
+        );
+        storage.set_string(
+            CanvasPreference::OSC_DIR_KEY,
+            self.preference.osc_dir.clone(),
+        );
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        // Never leave the avatar in the middle of a stroke.
+        self.stop_osc();
+    }
 }
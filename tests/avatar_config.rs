@@ -0,0 +1,197 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use vrc_canvas::osc::{
+    avatar_config::{self, AvatarConfig, MappingIssue},
+    pen_handle::profile::{AvatarProfile, ParameterType},
+    OscConfig, VrcCanvasError,
+};
+
+const PEN_AVATAR: &str = "avtr_11111111-1111-1111-1111-111111111111";
+const PLAIN_AVATAR: &str = "avtr_22222222-2222-2222-2222-222222222222";
+const OTHER_USER_AVATAR: &str = "avtr_44444444-4444-4444-4444-444444444444";
+
+fn osc_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/OSC")
+}
+
+fn load(avatar_id: &str) -> AvatarConfig {
+    avatar_config::load_config(&osc_dir(), Some(avatar_id)).unwrap()
+}
+
+/// Copy the fixtures, so the modified times can be changed.
+fn copy_dir(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+
+    for entry in std::fs::read_dir(from).unwrap() {
+        let path = entry.unwrap().path();
+        let target = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            copy_dir(&path, &target);
+        } else {
+            std::fs::copy(&path, &target).unwrap();
+        }
+    }
+}
+
+fn touch(path: &Path, modified: SystemTime) {
+    std::fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+}
+
+#[test]
+fn find_configs_of_every_user() {
+    let mut names = avatar_config::find_configs(&osc_dir())
+        .unwrap()
+        .into_iter()
+        .map(|path| path.file_stem().unwrap().to_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+
+    assert_eq!(names, [PEN_AVATAR, PLAIN_AVATAR, OTHER_USER_AVATAR]);
+}
+
+#[test]
+fn load_config_by_id() {
+    let config = load(PEN_AVATAR);
+    assert_eq!(config.id, PEN_AVATAR);
+    assert_eq!(config.name, "Pen Avatar");
+
+    assert_eq!(load(OTHER_USER_AVATAR).name, "Other User Avatar");
+
+    assert!(matches!(
+        avatar_config::load_config(&osc_dir(), Some("avtr_unknown")),
+        Err(VrcCanvasError::AvatarConfigNotFound(id)) if id == "avtr_unknown"
+    ));
+}
+
+#[test]
+fn load_reports_path() {
+    let path = osc_dir().join("avtr_missing.json");
+
+    assert!(matches!(
+        AvatarConfig::load(&path),
+        Err(VrcCanvasError::InvalidAvatarConfig { path: reported, .. }) if reported == path
+    ));
+}
+
+#[test]
+fn load_config_falls_back_to_latest() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("avatar_config_latest");
+    let _ = std::fs::remove_dir_all(&dir);
+    copy_dir(&osc_dir(), &dir);
+
+    let path = |user: &str, avatar: &str| {
+        dir.join(user)
+            .join("Avatars")
+            .join(format!("{}.json", avatar))
+    };
+    let pen = path("usr_00000000-0000-0000-0000-000000000000", PEN_AVATAR);
+    let plain = path("usr_00000000-0000-0000-0000-000000000000", PLAIN_AVATAR);
+    let other = path(
+        "usr_33333333-3333-3333-3333-333333333333",
+        OTHER_USER_AVATAR,
+    );
+
+    let now = SystemTime::now();
+    touch(&pen, now - Duration::from_secs(60));
+    touch(&plain, now - Duration::from_secs(30));
+    touch(&other, now);
+    assert_eq!(
+        avatar_config::load_config(&dir, None).unwrap().id,
+        OTHER_USER_AVATAR
+    );
+
+    touch(&pen, now + Duration::from_secs(30));
+    assert_eq!(
+        avatar_config::load_config(&dir, None).unwrap().id,
+        PEN_AVATAR
+    );
+}
+
+#[test]
+fn from_json_strips_bom() {
+    let bytes = std::fs::read(
+        osc_dir()
+            .join("usr_00000000-0000-0000-0000-000000000000/Avatars")
+            .join(format!("{}.json", PEN_AVATAR)),
+    )
+    .unwrap();
+    // The fixture is written with the byte order mark as VRChat does.
+    assert!(bytes.starts_with("\u{feff}".as_bytes()));
+
+    let config = AvatarConfig::from_json(std::str::from_utf8(&bytes).unwrap()).unwrap();
+    assert_eq!(config.id, PEN_AVATAR);
+
+    let output_only = config
+        .parameters
+        .iter()
+        .find(|parameter| parameter.name == "VelocityX")
+        .unwrap();
+    assert!(output_only.input.is_none());
+    assert_eq!(
+        output_only.output.as_ref().unwrap().ty,
+        ParameterType::Float
+    );
+}
+
+#[test]
+fn validate_reports_issues() {
+    let config = OscConfig::default();
+    let issues = load(PEN_AVATAR).validate(&AvatarProfile::default(), &config);
+
+    assert!(issues.contains(&MappingIssue::WrongType {
+        channel: "width",
+        addr: config.parameter_addr("/Pen_Width"),
+        expected: ParameterType::Float,
+        found: ParameterType::Int,
+    }));
+    assert!(issues.contains(&MappingIssue::Missing {
+        channel: "pen_down",
+        addr: config.parameter_addr("/on_drawing"),
+    }));
+
+    let channels = issues
+        .iter()
+        .map(|issue| match issue {
+            MappingIssue::Missing { channel, .. } | MappingIssue::WrongType { channel, .. } => {
+                *channel
+            }
+        })
+        .collect::<Vec<_>>();
+    for channel in ["x", "y", "enabled", "color"] {
+        assert!(!channels.contains(&channel), "{} is reported", channel);
+    }
+}
+
+#[test]
+fn suggest_profile_prefers_pen_parameters() {
+    let profile = load(PEN_AVATAR)
+        .suggest_profile(&OscConfig::default())
+        .unwrap();
+
+    assert_eq!(profile.name, "Pen Avatar");
+    assert_eq!(profile.avatars, [PEN_AVATAR]);
+    // Not `VelocityX`, which VRChat doesn't receive, nor `Scale_X` or `Opacity`.
+    assert_eq!(profile.x.addr, "/Pen_X");
+    assert_eq!(profile.y.addr, "/Pen_Y");
+    assert_eq!(profile.x.range, AvatarProfile::default().x.range);
+    assert_eq!(profile.enabled.unwrap().addr, "/Pen_Enabled");
+    assert_eq!(profile.pen_down.unwrap().addr, "/Pen_Draw");
+    assert_eq!(profile.color.unwrap().addr, "/Pen_Color");
+    // `Pen_Width` is an int, which the width isn't sent as.
+    assert_eq!(profile.width, None);
+    assert_eq!(profile.hue, None);
+}
+
+#[test]
+fn suggest_profile_needs_position() {
+    assert_eq!(
+        load(PLAIN_AVATAR).suggest_profile(&OscConfig::default()),
+        None
+    );
+}